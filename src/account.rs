@@ -1,28 +1,72 @@
 extern crate chrono;
 use self::chrono::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
 
 pub const TAX_ACCOUNTING_METHOD_FIFO: &str = "FIFO";
 pub const TAX_ACCOUNTING_METHOD_LIFO: &str = "LIFO";
 pub const TAX_ACCOUNTING_METHOD_HIFO: &str = "HIFO";
+/// Sells the lots designated for each sale, see `Account::withdraw_designated`.
+pub const TAX_ACCOUNTING_METHOD_SPECIFIC_ID: &str = "SPECIFIC_ID";
 
-#[derive(Debug)]
+/// A designation `withdraw_designated` could not honor in full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnhonoredDesignation {
+    pub buy_transaction_id: String,
+    /// The designated quantity that was not withdrawn.
+    pub quantity: f64,
+    /// Why: the lot is unknown, exhausted, not spendable yet or held in another wallet.
+    pub reason: &'static str,
+}
+
+/// Transaction id of the USD deposit every new USD account starts with.
+pub const OPENING_USD_TRANSACTION_ID: &str = "opening-USD";
+
+#[derive(Debug, Clone)]
 pub struct Deposit {
+    /// Id of the transaction that created the lot.
+    pub transaction_id: String,
+    /// Ids of the transactions that created the lots spent to acquire this one.
+    /// Empty for lots bought with USD or received from outside.
+    pub parents: Vec<String>,
+    /// The asset of the parent lots, the same transaction id can create lots of several assets.
+    pub parent_asset: Option<String>,
     pub datetime: DateTime<Utc>,
     pub quantity: f64,
     pub usd_value: f64,
     pub remaining_quantity: f64,
+    /// Wallet holding the lot once the lots are tracked per wallet, `None` while they are pooled.
+    pub wallet: Option<String>,
 }
 
 impl Deposit {
-    pub fn new(datetime: DateTime<Utc>, quantity: f64, usd_value: f64) -> Deposit {
+    pub fn new(transaction_id: &str, datetime: DateTime<Utc>, quantity: f64, usd_value: f64) -> Deposit {
         Deposit {
+            transaction_id: transaction_id.to_string(),
+            parents: vec![],
+            parent_asset: None,
             datetime,
             quantity,
             usd_value,
             remaining_quantity: quantity,
+            wallet: None,
         }
     }
 
+    /// A new lot of `quantity` of this one, with its date, parents and share of the cost basis.
+    fn part(&self, quantity: f64) -> Deposit {
+        let mut lot = Deposit::new(&self.transaction_id, self.datetime, quantity, self.usd_value * (quantity / self.quantity));
+        lot.parents = self.parents.clone();
+        lot.parent_asset = self.parent_asset.clone();
+        lot.wallet = self.wallet.clone();
+        lot
+    }
+
+    /// The part of `usd_value` that belongs to the remaining quantity.
+    pub fn remaining_cost_basis(&self) -> f64 {
+        self.usd_value * (self.remaining_quantity / self.quantity)
+    }
+
     fn claim(&mut self, quantity: f64) {
         if quantity > self.remaining_quantity {
             panic!("Not enough quantity remaining");
@@ -32,79 +76,346 @@ impl Deposit {
     }
 }
 
-#[derive(Debug)]
+/// Entry of the HIFO index: the lot with the highest cost per unit comes first,
+/// and of lots with the same unit cost the one deposited first.
+#[derive(Debug, Clone, PartialEq)]
+struct HighestCostLot {
+    unit_cost: f64,
+    index: usize,
+}
+
+impl Eq for HighestCostLot {}
+
+impl Ord for HighestCostLot {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.unit_cost
+            .total_cmp(&other.unit_cost)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for HighestCostLot {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// `deposits` keeps every lot ever deposited, in the order they were added, unless exhausted lots are
+/// dropped with `prune_exhausted_lots`. Lots must be added with `deposit` or `add_lot` so that the
+/// indexes used by `withdraw` stay in sync.
+#[derive(Debug, Clone)]
 pub struct Account {
     pub name: String,
     pub balance: f64,
     pub deposits: Vec<Deposit>,
+    /// Indexes of the lots with a remaining quantity, ordered by datetime. Used by FIFO and LIFO.
+    /// Exhausted lots are removed from the ends and, from time to time, from the middle.
+    open_lots: VecDeque<usize>,
+    /// The same lots ordered by unit cost for HIFO. Exhausted lots are removed when they reach the top.
+    highest_cost_lots: BinaryHeap<HighestCostLot>,
+    open_lot_count: usize,
+    /// Set once the lots have been allocated to wallets with `allocate_to_wallets`.
+    pub wallets_allocated: bool,
 }
 
 impl Account {
     pub fn new(name: String, balance: f64) -> Account {
-        let mut deposits = vec![];
+        let mut account = Account {
+            name,
+            balance: 0.0,
+            deposits: vec![],
+            open_lots: VecDeque::new(),
+            highest_cost_lots: BinaryHeap::new(),
+            open_lot_count: 0,
+            wallets_allocated: false,
+        };
 
         if balance > 0.0 {
             let some_date_time_in_the_past = NaiveDateTime::from_timestamp(1_000_000, 0);
             let existing_account_datetime =
                 DateTime::<Utc>::from_utc(some_date_time_in_the_past, Utc);
 
-            assert_eq!(name, "USD");
-            deposits.push(Deposit::new(existing_account_datetime, balance, balance));
+            assert_eq!(account.name, "USD");
+            account.add_lot(Deposit::new(OPENING_USD_TRANSACTION_ID, existing_account_datetime, balance, balance));
         }
 
-        Account {
-            name,
-            balance,
-            deposits,
+        account
+    }
+
+    pub fn deposit(&mut self, transaction_id: &str, datetime: DateTime<Utc>, quantity: f64, usd_value: f64, parents: Vec<String>) {
+        let mut deposit = Deposit::new(transaction_id, datetime, quantity, usd_value);
+        deposit.parents = parents;
+        self.add_lot(deposit);
+    }
+
+    /// Adds a lot carried over from a previous period, e.g. read back from an accounts file.
+    pub fn add_lot(&mut self, deposit: Deposit) {
+        self.balance += deposit.remaining_quantity;
+        let index = self.deposits.len();
+        if deposit.remaining_quantity > 0.0 {
+            let position = match self.open_lots.back() {
+                Some(last) if self.deposits[*last].datetime > deposit.datetime => self
+                    .open_lots
+                    .partition_point(|i| self.deposits[*i].datetime <= deposit.datetime),
+                _ => self.open_lots.len(),
+            };
+            self.open_lots.insert(position, index);
+            self.highest_cost_lots.push(HighestCostLot {
+                unit_cost: deposit.usd_value / deposit.quantity,
+                index,
+            });
+            self.open_lot_count += 1;
         }
+        self.deposits.push(deposit);
     }
 
-    pub fn deposit(&mut self, datetime: DateTime<Utc>, quantity: f64, usd_value: f64) {
-        self.deposits
-            .push(Deposit::new(datetime, quantity, usd_value));
+    pub fn withdraw(&mut self, datetime: DateTime<Utc>, quantity: f64, tax_accounting_method: &str, same_time_deposits_spendable: bool) -> Vec<Deposit> {
+        self.withdraw_lots(datetime, quantity, tax_accounting_method, None, same_time_deposits_spendable)
+    }
 
-        self.balance += quantity;
+    /// Withdraws from the lots held in `wallet` and from the lots that are not allocated to any wallet.
+    pub fn withdraw_from_wallet(&mut self, datetime: DateTime<Utc>, quantity: f64, tax_accounting_method: &str, wallet: &str, same_time_deposits_spendable: bool) -> Vec<Deposit> {
+        self.withdraw_lots(datetime, quantity, tax_accounting_method, Some(wallet), same_time_deposits_spendable)
     }
 
-    pub fn withdraw(&mut self, datetime: DateTime<Utc>, mut quantity: f64, tax_accounting_method: &str) -> Vec<Deposit> {
+    fn withdraw_lots(&mut self, datetime: DateTime<Utc>, mut quantity: f64, tax_accounting_method: &str, wallet: Option<&str>, same_time_deposits_spendable: bool) -> Vec<Deposit> {
         let mut withdrawn_quantities = vec![];
 
-        let calculate_withdrawals = |x: &mut Deposit, quantity: &mut f64, balance: &mut f64, withdrawn: &mut Vec<Deposit>| {
-            if *quantity <= 0.0 {
-                return;
-            };
-
-            let sold_quantity = x.remaining_quantity.min(*quantity);
-            let deposit = Deposit::new(
-                x.datetime,
-                sold_quantity,
-                x.usd_value * (sold_quantity / x.quantity),
-            );
-            withdrawn.push(deposit);
-            x.claim(sold_quantity);
-            *quantity -= sold_quantity;
-            *balance -= sold_quantity;
+        let is_spendable = |x: &Deposit| {
+            (x.datetime < datetime || (same_time_deposits_spendable && x.datetime == datetime)) && x.remaining_quantity > 0.0
         };
+        let is_in_wallet = |x: &Deposit| wallet.is_none() || x.wallet.is_none() || x.wallet.as_deref() == wallet;
 
-        let it = self.deposits.iter_mut().filter(|x| x.datetime < datetime && x.remaining_quantity > 0.0);
         if tax_accounting_method == TAX_ACCOUNTING_METHOD_LIFO {
-            for d in it.rev() {
-                calculate_withdrawals(d, &mut quantity, &mut self.balance, &mut withdrawn_quantities);
+            // Lots that are not spendable yet were deposited at the same datetime and are at the back.
+            for i in (0..self.open_lots.len()).rev() {
+                if quantity <= 0.0 {
+                    break;
+                }
+                let d = &mut self.deposits[self.open_lots[i]];
+                if is_spendable(d) && is_in_wallet(d) {
+                    calculate_withdrawals(d, &mut quantity, &mut self.balance, &mut withdrawn_quantities, &mut self.open_lot_count);
+                }
             }
         } else if tax_accounting_method == TAX_ACCOUNTING_METHOD_FIFO {
-            for d in it {
-                calculate_withdrawals(d, &mut quantity, &mut self.balance, &mut withdrawn_quantities);
+            for i in 0..self.open_lots.len() {
+                if quantity <= 0.0 {
+                    break;
+                }
+                let d = &mut self.deposits[self.open_lots[i]];
+                if d.remaining_quantity <= 0.0 || !is_in_wallet(d) {
+                    continue;
+                }
+                if !is_spendable(d) {
+                    break;
+                }
+                calculate_withdrawals(d, &mut quantity, &mut self.balance, &mut withdrawn_quantities, &mut self.open_lot_count);
             }
         } else if tax_accounting_method == TAX_ACCOUNTING_METHOD_HIFO {
-            let mut filtered_and_sorted_by_highest_cost_basis = it.collect::<Vec<&mut Deposit>>();
-            filtered_and_sorted_by_highest_cost_basis.sort_by(|a, b| b.usd_value.partial_cmp(&a.usd_value).unwrap());
-            for d in filtered_and_sorted_by_highest_cost_basis.iter_mut() {
-                calculate_withdrawals(*d, &mut quantity, &mut self.balance, &mut withdrawn_quantities);
+            let mut not_spendable_yet = vec![];
+            while quantity > 0.0 {
+                let lot = match self.highest_cost_lots.pop() {
+                    Some(lot) => lot,
+                    None => break,
+                };
+                let d = &mut self.deposits[lot.index];
+                if d.remaining_quantity <= 0.0 {
+                    continue;
+                }
+                if is_spendable(d) && is_in_wallet(d) {
+                    calculate_withdrawals(d, &mut quantity, &mut self.balance, &mut withdrawn_quantities, &mut self.open_lot_count);
+                }
+                if d.remaining_quantity > 0.0 {
+                    not_spendable_yet.push(lot);
+                }
             }
+            self.highest_cost_lots.extend(not_spendable_yet);
         } else {
             panic!("Unsupported tax_accounting_method:{}", tax_accounting_method);
         }
 
+        self.remove_exhausted_lots();
         withdrawn_quantities
     }
+
+    /// Withdraws up to the designated quantity from each of the designated lots, given by the id of the
+    /// transaction that created them. Lots that are exhausted or not spendable at `datetime` are skipped, so
+    /// less than `quantity` can be returned; the rest is left to `withdraw`. With a `wallet`, only the lots held
+    /// in it and the unallocated ones are withdrawn. The designations that could not be honored in full are
+    /// returned with the withdrawn lots.
+    pub fn withdraw_designated(&mut self, datetime: DateTime<Utc>, mut quantity: f64, designations: &[(&str, f64)], wallet: Option<&str>, same_time_deposits_spendable: bool) -> (Vec<Deposit>, Vec<UnhonoredDesignation>) {
+        let mut withdrawn_quantities = vec![];
+        let mut unhonored = vec![];
+        for (transaction_id, designated_quantity) in designations {
+            let mut designated_quantity = quantity.min(*designated_quantity);
+            let lots = self.open_lots.iter().filter(|i| self.deposits[**i].transaction_id == *transaction_id).cloned().collect::<Vec<usize>>();
+            let mut not_spendable_yet = false;
+            let mut in_other_wallets = false;
+            for i in lots {
+                let d = &mut self.deposits[i];
+                if d.remaining_quantity <= 0.0 {
+                    continue;
+                }
+                if wallet.is_some() && d.wallet.is_some() && d.wallet.as_deref() != wallet {
+                    in_other_wallets = true;
+                    continue;
+                }
+                if d.datetime < datetime || (same_time_deposits_spendable && d.datetime == datetime) {
+                    let wanted = designated_quantity;
+                    calculate_withdrawals(d, &mut designated_quantity, &mut self.balance, &mut withdrawn_quantities, &mut self.open_lot_count);
+                    quantity -= wanted - designated_quantity;
+                } else {
+                    not_spendable_yet = true;
+                }
+            }
+            if designated_quantity > 0.0 {
+                let reason = if not_spendable_yet {
+                    "not spendable yet"
+                } else if in_other_wallets {
+                    "held in another wallet"
+                } else if self.deposits.iter().any(|d| d.transaction_id == *transaction_id) {
+                    "exhausted"
+                } else {
+                    "unknown"
+                };
+                unhonored.push(UnhonoredDesignation {
+                    buy_transaction_id: transaction_id.to_string(),
+                    quantity: designated_quantity,
+                    reason,
+                });
+            }
+        }
+
+        self.remove_exhausted_lots();
+        (withdrawn_quantities, unhonored)
+    }
+
+    /// The lots with a remaining quantity, in the order `withdraw` would spend them with `tax_accounting_method`.
+    pub fn open_lots_in_order(&self, tax_accounting_method: &str) -> Vec<&Deposit> {
+        let mut lots = self
+            .open_lots
+            .iter()
+            .filter(|i| self.deposits[**i].remaining_quantity > 0.0)
+            .map(|i| (*i, &self.deposits[*i]))
+            .collect::<Vec<(usize, &Deposit)>>();
+        if tax_accounting_method == TAX_ACCOUNTING_METHOD_LIFO {
+            lots.reverse();
+        } else if tax_accounting_method == TAX_ACCOUNTING_METHOD_HIFO {
+            lots.sort_by(|(i1, d1), (i2, d2)| {
+                let lot1 = HighestCostLot { unit_cost: d1.usd_value / d1.quantity, index: *i1 };
+                let lot2 = HighestCostLot { unit_cost: d2.usd_value / d2.quantity, index: *i2 };
+                lot2.cmp(&lot1)
+            });
+        }
+        lots.into_iter().map(|(_, deposit)| deposit).collect()
+    }
+
+    /// Splits the open lots between wallets. Each allocation gives `quantity` of the lot created by the transaction
+    /// `buy_transaction_id` to `wallet`, keeping its date and its cost basis per unit. What the allocations don't
+    /// cover stays unallocated and can be spent from any wallet. An allocation is used up by the lots it covers, so
+    /// lots sharing a transaction id take it in turn. A lot that is split is kept, exhausted, as the record of the
+    /// acquisition, followed by its parts, which share its transaction id.
+    pub fn allocate_to_wallets(&mut self, allocations: &[(&str, &str, f64)]) {
+        let balance = self.balance;
+        let deposits = std::mem::take(&mut self.deposits);
+        self.open_lots.clear();
+        self.highest_cost_lots.clear();
+        self.open_lot_count = 0;
+        let mut unallocated_quantities = allocations.iter().map(|(_, _, quantity)| *quantity).collect::<Vec<f64>>();
+        for mut deposit in deposits {
+            let mut parts = vec![];
+            for ((id, wallet, _), unallocated) in allocations.iter().zip(unallocated_quantities.iter_mut()) {
+                if *id != deposit.transaction_id {
+                    continue;
+                }
+                let quantity = unallocated.min(deposit.remaining_quantity);
+                if quantity <= 0.0 {
+                    continue;
+                }
+                let mut lot = deposit.part(quantity);
+                lot.wallet = Some(wallet.to_string());
+                deposit.claim(quantity);
+                *unallocated -= quantity;
+                parts.push(lot);
+            }
+            if !parts.is_empty() && deposit.remaining_quantity > 0.0 {
+                // The unallocated part keeps its share of the cost basis.
+                let rest = deposit.part(deposit.remaining_quantity);
+                deposit.claim(rest.quantity);
+                parts.push(rest);
+            }
+            self.add_lot(deposit);
+            for lot in parts {
+                self.add_lot(lot);
+            }
+        }
+        self.balance = balance;
+        self.wallets_allocated = true;
+    }
+
+    /// Drops the exhausted lots from `deposits`, which bounds the memory used by long histories.
+    /// Lineage and the lots behind past tax events are no longer available afterwards.
+    pub fn prune_exhausted_lots(&mut self) {
+        if self.deposits.len() == self.open_lot_count {
+            return;
+        }
+
+        let balance = self.balance;
+        let deposits = std::mem::take(&mut self.deposits);
+        self.open_lots.clear();
+        self.highest_cost_lots.clear();
+        self.open_lot_count = 0;
+        for deposit in deposits.into_iter().filter(|d| d.remaining_quantity > 0.0) {
+            self.add_lot(deposit);
+        }
+        // Keep the balance as tracked by withdraw rather than the sum of the remaining quantities.
+        self.balance = balance;
+    }
+
+    fn remove_exhausted_lots(&mut self) {
+        let deposits = &self.deposits;
+        while self.open_lots.front().is_some_and(|i| deposits[*i].remaining_quantity <= 0.0) {
+            self.open_lots.pop_front();
+        }
+        while self.open_lots.back().is_some_and(|i| deposits[*i].remaining_quantity <= 0.0) {
+            self.open_lots.pop_back();
+        }
+        while self.highest_cost_lots.peek().is_some_and(|lot| deposits[lot.index].remaining_quantity <= 0.0) {
+            self.highest_cost_lots.pop();
+        }
+
+        // Lots exhausted in the middle of an index, e.g. by HIFO in the FIFO index, are removed
+        // once they make up most of it.
+        if self.open_lots.len() > 2 * self.open_lot_count + 32 {
+            self.open_lots.retain(|i| deposits[*i].remaining_quantity > 0.0);
+        }
+        if self.highest_cost_lots.len() > 2 * self.open_lot_count + 32 {
+            self.highest_cost_lots.retain(|lot| deposits[lot.index].remaining_quantity > 0.0);
+        }
+    }
+}
+
+/// Withdraws up to `quantity` from the lot `x`.
+fn calculate_withdrawals(x: &mut Deposit, quantity: &mut f64, balance: &mut f64, withdrawn: &mut Vec<Deposit>, open_lot_count: &mut usize) {
+    if *quantity <= 0.0 {
+        return;
+    };
+
+    let sold_quantity = x.remaining_quantity.min(*quantity);
+    let mut deposit = Deposit::new(
+        &x.transaction_id,
+        x.datetime,
+        sold_quantity,
+        x.usd_value * (sold_quantity / x.quantity),
+    );
+    deposit.parents = x.parents.clone();
+    deposit.parent_asset = x.parent_asset.clone();
+    deposit.wallet = x.wallet.clone();
+    withdrawn.push(deposit);
+    x.claim(sold_quantity);
+    if x.remaining_quantity <= 0.0 {
+        *open_lot_count -= 1;
+    }
+    *quantity -= sold_quantity;
+    *balance -= sold_quantity;
 }
@@ -0,0 +1,172 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Serialize;
+
+use account::{Account, TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_HIFO, TAX_ACCOUNTING_METHOD_LIFO};
+use period::tax_year_of;
+use schedule_d::{schedule_d_by_year, CapitalLossCarryforward};
+use tax::{calculate_capital_gains_by_year, calculate_income, round_to_dollars, ProcessingOptions, Transaction, CAPITAL_GAIN_TYPE_LONG};
+use tax_estimate::TaxEstimator;
+
+pub const TAX_ACCOUNTING_METHODS: [&str; 3] = [TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_LIFO, TAX_ACCOUNTING_METHOD_HIFO];
+
+pub const DEFAULT_SHORT_TERM_RATE: f64 = 0.24;
+pub const DEFAULT_LONG_TERM_RATE: f64 = 0.15;
+
+/// Flat tax rates used to estimate the tax on the gains of a year.
+#[derive(Debug, Clone)]
+pub struct TaxRates {
+    pub short_term: f64,
+    pub long_term: f64,
+}
+
+impl Default for TaxRates {
+    fn default() -> TaxRates {
+        TaxRates {
+            short_term: DEFAULT_SHORT_TERM_RATE,
+            long_term: DEFAULT_LONG_TERM_RATE,
+        }
+    }
+}
+
+impl TaxRates {
+    /// A net loss of one term offsets the gain of the other term before the rates are applied.
+    /// A net loss for the year is not taxed.
+    pub fn estimate(&self, short_term_gain: f64, long_term_gain: f64) -> f64 {
+        let (short_term_gain, long_term_gain) = if short_term_gain < 0.0 {
+            (0.0, long_term_gain + short_term_gain)
+        } else if long_term_gain < 0.0 {
+            (short_term_gain + long_term_gain, 0.0)
+        } else {
+            (short_term_gain, long_term_gain)
+        };
+        round_to_dollars(short_term_gain.max(0.0) * self.short_term + long_term_gain.max(0.0) * self.long_term)
+    }
+}
+
+/// The flat rates only tax the gains, the income is the same with every method.
+impl TaxEstimator for TaxRates {
+    fn estimate_tax(&self, _year: i32, _crypto_income: f64, short_term_gain: f64, long_term_gain: f64) -> f64 {
+        self.estimate(short_term_gain, long_term_gain)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MethodComparisonRow {
+    pub method: String,
+    pub year: i32,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub total_gain: f64,
+    pub estimated_tax: f64,
+    /// Cost basis of the lots still held at the end of the year, i.e. the gains deferred to later years.
+    pub basis_carried_forward: f64,
+}
+
+/// Replays the transactions with every accounting method and sums the gains of each tax year. The tax is
+/// estimated on the gains netted with the losses carried over from the earlier years of the same method.
+/// Rows are ordered by method, then year.
+pub fn compare_accounting_methods(
+    transactions: &[Transaction],
+    accounts: &HashMap<String, Account>,
+    options: &ProcessingOptions,
+    opening_carryforward: Option<&CapitalLossCarryforward>,
+    filing_status: &str,
+    time_zone: &FixedOffset,
+    estimator: &dyn TaxEstimator,
+) -> Vec<MethodComparisonRow> {
+    let mut rows = vec![];
+    let income = calculate_income(transactions);
+
+    for method in TAX_ACCOUNTING_METHODS.iter() {
+        let mut method_options = options.clone();
+        method_options.tax_accounting_method = method.to_string();
+        method_options.method_schedule = None;
+        let (tax_events, year_end_accounts) = calculate_capital_gains_by_year(&mut transactions.to_vec(), accounts.clone(), &method_options, time_zone);
+        let netted_by_year: BTreeMap<i32, (f64, f64)> = schedule_d_by_year(&tax_events, opening_carryforward, filing_status, time_zone)
+            .into_iter()
+            .map(|netted| (netted.year, (netted.net_short_term, netted.net_long_term)))
+            .collect();
+
+        for (year, year_end_accounts) in year_end_accounts.iter() {
+            let mut short_term_gain = 0.0;
+            let mut long_term_gain = 0.0;
+            for tax_event in tax_events.iter().filter(|e| tax_year_of(e.sell_date, time_zone) == *year) {
+                if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+                    long_term_gain += tax_event.gain;
+                } else {
+                    short_term_gain += tax_event.gain;
+                }
+            }
+            let crypto_income: f64 = income
+                .iter()
+                .filter(|i| tax_year_of(i.datetime, time_zone) == *year)
+                .map(|i| i.usd_value)
+                .sum();
+            let basis_carried_forward: f64 = year_end_accounts
+                .iter()
+                .filter(|(asset, _)| asset.as_str() != "USD")
+                .flat_map(|(_, account)| account.deposits.iter())
+                .filter(|d| d.remaining_quantity > 0.0)
+                .map(|d| d.remaining_cost_basis())
+                .sum();
+            let (net_short_term, net_long_term) = netted_by_year.get(year).cloned().unwrap_or_default();
+
+            rows.push(MethodComparisonRow {
+                method: method.to_string(),
+                year: *year,
+                short_term_gain: round_to_dollars(short_term_gain),
+                long_term_gain: round_to_dollars(long_term_gain),
+                total_gain: round_to_dollars(short_term_gain + long_term_gain),
+                estimated_tax: estimator.estimate_tax(*year, crypto_income, net_short_term, net_long_term),
+                basis_carried_forward: round_to_dollars(basis_carried_forward),
+            });
+        }
+    }
+
+    rows
+}
+
+/// Prints the rows of every method next to each other, year by year.
+pub fn print_method_comparison(rows: &[MethodComparisonRow]) {
+    let mut years = rows.iter().map(|r| r.year).collect::<Vec<i32>>();
+    years.sort_unstable();
+    years.dedup();
+
+    println!("{:<6} {:<6} {:>16} {:>16} {:>16} {:>16} {:>16}", "Year", "Method", "Short term", "Long term", "Total", "Estimated tax", "Basis forward");
+    for year in years {
+        for row in rows.iter().filter(|r| r.year == year) {
+            println!(
+                "{:<6} {:<6} {:>16.2} {:>16.2} {:>16.2} {:>16.2} {:>16.2}",
+                row.year, row.method, row.short_term_gain, row.long_term_gain, row.total_gain, row.estimated_tax, row.basis_carried_forward
+            );
+        }
+    }
+
+    println!("Totals over all years:");
+    for method in TAX_ACCOUNTING_METHODS.iter() {
+        let method_rows = rows.iter().filter(|r| r.method == *method).collect::<Vec<&MethodComparisonRow>>();
+        let total_gain: f64 = method_rows.iter().map(|r| r.total_gain).sum();
+        let estimated_tax: f64 = method_rows.iter().map(|r| r.estimated_tax).sum();
+        let basis_carried_forward = method_rows.last().map(|r| r.basis_carried_forward).unwrap_or(0.0);
+        println!(
+            "{:<6} total gain {:.2}, estimated tax {:.2}, basis carried forward {:.2}",
+            method, total_gain, estimated_tax, basis_carried_forward
+        );
+    }
+}
+
+pub fn save_method_comparison_to_file(rows: &[MethodComparisonRow], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
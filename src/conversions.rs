@@ -9,6 +9,8 @@ use self::chrono::prelude::*;
 use std::error::Error;
 
 use tax::Transaction;
+use margin::{MarginTransaction, MARGIN_TYPE_ROLLOVER};
+use prices::PriceDatabase;
 
 use std::collections::HashMap;
 
@@ -48,6 +50,14 @@ fn kraken_dest_asset<'a>(pair: &'a str, buy_or_sell: &str) -> &'a str {
     }
 }
 
+fn kraken_base_asset(pair: &str) -> &str {
+    &KRAKEN_PAIRS.get(pair).unwrap().0
+}
+
+fn kraken_quote_asset(pair: &str) -> &str {
+    &KRAKEN_PAIRS.get(pair).unwrap().1
+}
+
 fn kraken_orig_asset<'a>(pair: &'a str, buy_or_sell: &str) -> &'a str {
     if buy_or_sell == "sell" {
         &KRAKEN_PAIRS.get(pair).unwrap().0
@@ -99,6 +109,42 @@ mod kraken_date_format {
     }
 }
 
+/// An entry of the Kraken ledgers export. Only the fees of margin positions are read from it.
+#[derive(Debug, Deserialize)]
+struct KrakenLedgerEntry {
+    txid: String,
+    refid: String,
+    #[serde(with = "kraken_date_format")]
+    time: DateTime<Utc>,
+    #[serde(rename = "type")]
+    type_: String,
+    asset: String,
+    fee: f64,
+}
+
+const KRAKEN_LEDGER_TYPE_ROLLOVER: &str = "rollover";
+/// Opening fees of margin positions, and their settlement.
+const KRAKEN_LEDGER_TYPE_MARGIN: &str = "margin";
+
+/// Ledger assets are prefixed with X for crypto and Z for fiat, e.g. XXBT and ZUSD.
+fn kraken_ledger_asset(asset: &str) -> &str {
+    if asset.len() == 4 && (asset.starts_with('X') || asset.starts_with('Z')) {
+        &asset[1..]
+    } else {
+        asset
+    }
+}
+
+fn read_kraken_ledger(file_path: &str) -> Result<Vec<KrakenLedgerEntry>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut entries = vec![];
+    for entry in reader.deserialize() {
+        entries.push(entry?);
+    }
+    Ok(entries)
+}
+
 fn read_kraken_transactions(file_path: &str) -> Result<Vec<KrakenTransaction>, Box<Error>> {
     let file = File::open(file_path)?;
 
@@ -112,13 +158,43 @@ fn read_kraken_transactions(file_path: &str) -> Result<Vec<KrakenTransaction>, B
     Ok(transactions)
 }
 
-pub fn process_kraken_transactions(file_path: &str, out_file: &str) {
+/// Converts the Kraken trades to PTT transactions. Margin trades, and the rollover and margin fees of the ledger
+/// when it is given, are written to `margin_out_file` instead, see `margin::calculate_margin_gains`. Each fee
+/// belongs to the position of the trade its `refid` names.
+pub fn process_kraken_transactions(file_path: &str, out_file: &str, margin_out_file: &str, ledger_file: Option<&str>, prices: Option<&PriceDatabase>, price_interpolation: &str) {
     let mut transactions = read_kraken_transactions(file_path).expect("Can't read transactions");
     transactions.sort_by(|t1, t2| t1.time.cmp(&t2.time));
 
     let mut ptt_transactons = vec![];
+    let mut margin_transactions = vec![];
 
     for kraken_transaction in transactions.iter() {
+        if kraken_transaction.margin > 0.0 {
+            let quote_asset = kraken_quote_asset(&kraken_transaction.pair);
+            let (usd_value, usd_fee) = if quote_asset == "USD" {
+                (kraken_transaction.cost, Some(kraken_transaction.fee))
+            } else {
+                match quote_to_usd(prices, quote_asset, kraken_transaction.cost, kraken_transaction.fee, kraken_transaction.time, price_interpolation) {
+                    (Some(usd_value), usd_fee) => (usd_value, usd_fee),
+                    (None, _) => {
+                        eprintln!("margin trade {}: no {} price, ignored", kraken_transaction.txid, quote_asset);
+                        continue;
+                    }
+                }
+            };
+            margin_transactions.push(MarginTransaction {
+                id: kraken_transaction.txid.to_owned(),
+                datetime: kraken_transaction.time,
+                asset: kraken_base_asset(&kraken_transaction.pair).to_owned(),
+                type_: kraken_transaction.type_.to_owned(),
+                quantity: kraken_transaction.vol,
+                usd_value,
+                usd_fee,
+                position_id: None,
+            });
+            continue;
+        }
+
         let orig_asset =
             kraken_orig_asset(&kraken_transaction.pair, &kraken_transaction.type_).to_string();
 
@@ -127,6 +203,14 @@ pub fn process_kraken_transactions(file_path: &str, out_file: &str) {
             origin_wallet = WALLET_BANK.to_string();
         }
 
+        let quote_asset = kraken_quote_asset(&kraken_transaction.pair);
+        let quote_value = (kraken_transaction.vol * kraken_transaction.price) + kraken_transaction.fee;
+        let (usd_value, usd_fee) = if quote_asset == "USD" {
+            (Some(quote_value), Some(kraken_transaction.fee))
+        } else {
+            quote_to_usd(prices, quote_asset, quote_value, kraken_transaction.fee, kraken_transaction.time, price_interpolation)
+        };
+
         ptt_transactons.push(Transaction {
             id: kraken_transaction.txid.to_owned(),
             datetime: kraken_transaction.time,
@@ -140,8 +224,11 @@ pub fn process_kraken_transactions(file_path: &str, out_file: &str) {
             )
             .to_owned(),
             destination_quantity: kraken_transaction.vol,
-            usd_value: (kraken_transaction.vol * kraken_transaction.price) + kraken_transaction.fee,
-            usd_fee: Some(kraken_transaction.fee),
+            usd_value,
+            usd_fee,
+            fee_asset: Some(quote_asset.to_string()),
+            sequence: None,
+            type_: None,
         });
     }
 
@@ -157,6 +244,47 @@ pub fn process_kraken_transactions(file_path: &str, out_file: &str) {
             .ok()
             .expect("Unable to write to output file.");
     }
+
+    if let Some(ledger_file) = ledger_file {
+        let entries = read_kraken_ledger(ledger_file).expect("Can't read ledger");
+        let is_margin_fee = |e: &&KrakenLedgerEntry| (e.type_ == KRAKEN_LEDGER_TYPE_ROLLOVER || e.type_ == KRAKEN_LEDGER_TYPE_MARGIN) && e.fee != 0.0;
+        for entry in entries.iter().filter(is_margin_fee) {
+            let trade = transactions
+                .iter()
+                .find(|t| t.margin > 0.0 && (t.txid == entry.refid || t.ordertxid == entry.refid));
+            let trade = match trade {
+                Some(trade) => trade,
+                None => {
+                    eprintln!("ledger entry {}: no margin trade {}, {} fee ignored", entry.txid, entry.refid, entry.type_);
+                    continue;
+                }
+            };
+            let fee_asset = kraken_ledger_asset(&entry.asset);
+            let usd_fee = if fee_asset == "USD" {
+                Some(entry.fee)
+            } else {
+                quote_to_usd(prices, fee_asset, 0.0, entry.fee, entry.time, price_interpolation).1
+            };
+            margin_transactions.push(MarginTransaction {
+                id: entry.txid.to_owned(),
+                datetime: entry.time,
+                asset: kraken_base_asset(&trade.pair).to_owned(),
+                type_: MARGIN_TYPE_ROLLOVER.to_string(),
+                quantity: 0.0,
+                usd_value: 0.0,
+                usd_fee,
+                position_id: Some(trade.txid.to_owned()),
+            });
+        }
+        margin_transactions.sort_by_key(|t| t.datetime);
+    }
+
+    if !margin_transactions.is_empty() {
+        let mut writer = csv::Writer::from_writer(File::create(margin_out_file).expect("Unable to create margin output file."));
+        for margin_transaction in margin_transactions.iter() {
+            writer.serialize(margin_transaction).expect("Unable to write to margin output file.");
+        }
+    }
 }
 
 lazy_static! {
@@ -237,7 +365,7 @@ fn read_bittrex_transactions(file_path: &str) -> Result<Vec<BittrexTransaction>,
     Ok(transactions)
 }
 
-pub fn process_bittrex_transactions(file_path: &str, out_file: &str) {
+pub fn process_bittrex_transactions(file_path: &str, out_file: &str, prices: Option<&PriceDatabase>, price_interpolation: &str) {
     let mut transactions = read_bittrex_transactions(file_path).expect("Can't read transactions");
     transactions.sort_by(|t1, t2| t1.TimeStamp.cmp(&t2.TimeStamp));
 
@@ -250,7 +378,15 @@ pub fn process_bittrex_transactions(file_path: &str, out_file: &str) {
             &bittrex_transaction.Exchange,
             &bittrex_transaction.OrderType,
         );
-        let parse_from_str = NaiveDateTime::parse_from_str;
+        let quote_asset = &BITTREX_PAIRS.get(&bittrex_transaction.Exchange).unwrap().0;
+        let (usd_value, usd_fee) = quote_to_usd(
+            prices,
+            quote_asset,
+            (bittrex_transaction.Quantity * bittrex_transaction.PricePerUnit) + bittrex_transaction.Commission,
+            bittrex_transaction.Commission,
+            bittrex_transaction.TimeStamp,
+            price_interpolation,
+        );
 
         ptt_transactons.push(Transaction {
             id: bittrex_transaction.Uuid.to_owned(),
@@ -265,9 +401,11 @@ pub fn process_bittrex_transactions(file_path: &str, out_file: &str) {
             )
             .to_owned(),
             destination_quantity: bittrex_transaction.Price,
-            usd_value: (bittrex_transaction.Quantity * bittrex_transaction.PricePerUnit) // TODO get real USD value
-                + bittrex_transaction.Commission,
-            usd_fee: Some(bittrex_transaction.Commission),
+            usd_value,
+            usd_fee,
+            fee_asset: Some(quote_asset.to_string()),
+            sequence: None,
+            type_: None,
         });
     }
 
@@ -285,6 +423,19 @@ pub fn process_bittrex_transactions(file_path: &str, out_file: &str) {
     }
 }
 
+/// Converts an amount and fee denominated in a non-USD quote asset to USD.
+/// Without a price for the quote asset the USD value is left empty so that it
+/// can be filled from the price database when the transactions are processed.
+fn quote_to_usd(prices: Option<&PriceDatabase>, quote_asset: &str, quote_value: f64, quote_fee: f64, datetime: DateTime<Utc>, price_interpolation: &str) -> (Option<f64>, Option<f64>) {
+    match prices.and_then(|p| p.price_at(quote_asset, datetime, price_interpolation)) {
+        Some(price) => (Some(quote_value * price), Some(quote_fee * price)),
+        None => {
+            eprintln!("No {} price at {}, usd_value left empty", quote_asset, datetime);
+            (None, None)
+        }
+    }
+}
+
 mod bittrex_date_format {
     use super::chrono::{DateTime, TimeZone, Utc};
     use serde::{self, Deserialize, Deserializer, Serializer};
@@ -0,0 +1,45 @@
+extern crate csv;
+
+use std::error::Error;
+use std::fs::File;
+
+use serde::Deserialize;
+
+/// Declares that converting `asset` to `equivalent_asset`, or back, e.g. wrapping ETH into WETH, carries the lots over
+/// unchanged when `non_taxable` is true. With `false` the conversion stays a taxable trade.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EquivalenceRule {
+    pub asset: String,
+    pub equivalent_asset: String,
+    pub non_taxable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct EquivalenceRules {
+    rules: Vec<EquivalenceRule>,
+}
+
+impl EquivalenceRules {
+    pub fn new(rules: Vec<EquivalenceRule>) -> EquivalenceRules {
+        EquivalenceRules { rules }
+    }
+
+    /// Whether converting `origin_asset` into `destination_asset` carries the lots over.
+    pub fn is_non_taxable(&self, origin_asset: &str, destination_asset: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.non_taxable
+                && ((rule.asset == origin_asset && rule.equivalent_asset == destination_asset)
+                    || (rule.asset == destination_asset && rule.equivalent_asset == origin_asset))
+        })
+    }
+}
+
+pub fn read_equivalence_rules(file_path: &str) -> Result<EquivalenceRules, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut rules = vec![];
+    for rule in reader.deserialize() {
+        rules.push(rule?);
+    }
+    Ok(EquivalenceRules::new(rules))
+}
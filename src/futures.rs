@@ -0,0 +1,239 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use period::{tax_year_of, TaxPeriod};
+use prices::PriceDatabase;
+use tax::round_to_dollars;
+use unrealized::DUST_QUANTITY;
+
+pub const FUTURES_TYPE_BUY: &str = "buy";
+pub const FUTURES_TYPE_SELL: &str = "sell";
+
+/// Section 1256 contracts are taxed 60% long term and 40% short term, however long they were held.
+pub const SECTION_1256_LONG_TERM_SHARE: f64 = 0.6;
+
+/// A fill of a regulated futures contract. The P&L of a contract is `quantity * price * multiplier`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FuturesFill {
+    pub id: String,
+    pub datetime: DateTime<Utc>,
+    pub contract: String,
+    /// `buy` or `sell`.
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub quantity: f64,
+    pub price: f64,
+    /// Units of the underlying per contract, 1 when empty.
+    #[serde(default)]
+    pub multiplier: Option<f64>,
+    pub usd_fee: Option<f64>,
+}
+
+/// A settlement price of a contract. The final settlement closes the positions still open in the contract.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FuturesSettlement {
+    pub contract: String,
+    pub datetime: DateTime<Utc>,
+    pub price: f64,
+    #[serde(rename = "final", default)]
+    pub final_settlement: Option<bool>,
+}
+
+/// The open part of a fill, with its price reset to the mark of every year end.
+#[derive(Debug)]
+struct FuturesLot {
+    /// 1 for a long position, -1 for a short one.
+    side: f64,
+    quantity: f64,
+    price: f64,
+    multiplier: f64,
+}
+
+/// The gain of a contract in a year: the positions closed in the year, from their last mark or open price, and the
+/// positions still open marked to market at the year end.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Section1256Contract {
+    pub year: i32,
+    pub contract: String,
+    pub realized: f64,
+    pub marked_to_market: f64,
+    pub fees: f64,
+    pub gain: f64,
+}
+
+/// Part I of Form 6781: the net gain of the Section 1256 contracts of a year and its 60/40 split.
+#[derive(Debug, Clone, Serialize)]
+pub struct Form6781Year {
+    pub year: i32,
+    pub total_gain: f64,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+}
+
+pub fn read_futures_fills(file_path: &str) -> Result<Vec<FuturesFill>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut fills = vec![];
+    for fill in reader.deserialize() {
+        fills.push(fill?);
+    }
+    Ok(fills)
+}
+
+pub fn read_futures_settlements(file_path: &str) -> Result<Vec<FuturesSettlement>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut settlements = vec![];
+    for settlement in reader.deserialize() {
+        settlements.push(settlement?);
+    }
+    Ok(settlements)
+}
+
+/// Replays the fills and final settlements by datetime, years evaluated in `time_zone`. A fill opposite to the open
+/// position closes its lots first in, first out. At the end of every year the open positions are marked to market
+/// at the price of the contract in `prices`, or else its last settlement price, and the mark becomes their new
+/// price. The last year is only marked when it has ended by `as_of`.
+pub fn calculate_section_1256(fills: &[FuturesFill], settlements: &[FuturesSettlement], prices: Option<&PriceDatabase>, price_interpolation: &str, time_zone: &FixedOffset, as_of: DateTime<Utc>) -> Vec<Section1256Contract> {
+    let mut fills = fills.iter().collect::<Vec<&FuturesFill>>();
+    fills.sort_by_key(|f| f.datetime);
+    let mut settlements = settlements.iter().collect::<Vec<&FuturesSettlement>>();
+    settlements.sort_by_key(|s| s.datetime);
+    let final_settlements = settlements.iter().filter(|s| s.final_settlement.unwrap_or(false)).cloned().collect::<Vec<&FuturesSettlement>>();
+
+    let mut rows: BTreeMap<(i32, String), Section1256Contract> = BTreeMap::new();
+    let mut positions: BTreeMap<String, VecDeque<FuturesLot>> = BTreeMap::new();
+
+    let mark_to_market = |year: i32, positions: &mut BTreeMap<String, VecDeque<FuturesLot>>, rows: &mut BTreeMap<(i32, String), Section1256Contract>| {
+        let year_end = TaxPeriod::tax_year(year, time_zone).end;
+        for (contract, lots) in positions.iter_mut().filter(|(_, lots)| !lots.is_empty()) {
+            let price = prices
+                .and_then(|p| p.price_at(contract, year_end, price_interpolation))
+                .or_else(|| settlements.iter().rev().find(|s| &s.contract == contract && s.datetime < year_end).map(|s| s.price))
+                .or_else(|| {
+                    let fill = fills.iter().rev().find(|f| &f.contract == contract && f.datetime < year_end)?;
+                    eprintln!("No price for {} at the end of {}, marked at the last fill price", contract, year);
+                    Some(fill.price)
+                })
+                .expect("a fill of every open contract");
+            let row = rows.entry((year, contract.clone())).or_default();
+            for lot in lots.iter_mut() {
+                row.marked_to_market += (price - lot.price) * lot.quantity * lot.multiplier * lot.side;
+                lot.price = price;
+            }
+        }
+    };
+
+    let mut events = fills.iter().map(|f| (f.datetime, Some(*f), None)).collect::<Vec<(DateTime<Utc>, Option<&FuturesFill>, Option<&FuturesSettlement>)>>();
+    events.extend(final_settlements.iter().map(|s| (s.datetime, None, Some(*s))));
+    events.sort_by_key(|e| e.0);
+
+    let mut current_year = match events.first() {
+        Some(event) => tax_year_of(event.0, time_zone),
+        None => return vec![],
+    };
+    for (datetime, fill, settlement) in events {
+        let year = tax_year_of(datetime, time_zone);
+        while current_year < year {
+            mark_to_market(current_year, &mut positions, &mut rows);
+            current_year += 1;
+        }
+
+        if let Some(fill) = fill {
+            let side = if fill.type_ == FUTURES_TYPE_BUY {
+                1.0
+            } else if fill.type_ == FUTURES_TYPE_SELL {
+                -1.0
+            } else {
+                eprintln!("futures fill {}: unknown type {}, ignored", fill.id, fill.type_);
+                continue;
+            };
+            let row = rows.entry((year, fill.contract.clone())).or_default();
+            row.fees += fill.usd_fee.unwrap_or_default();
+            let lots = positions.entry(fill.contract.clone()).or_default();
+            let mut quantity = fill.quantity;
+            while quantity > DUST_QUANTITY && lots.front().is_some_and(|lot| lot.side != side) {
+                let lot = lots.front_mut().unwrap();
+                let closed_quantity = lot.quantity.min(quantity);
+                row.realized += (fill.price - lot.price) * closed_quantity * lot.multiplier * lot.side;
+                lot.quantity -= closed_quantity;
+                if lot.quantity <= DUST_QUANTITY {
+                    lots.pop_front();
+                }
+                quantity -= closed_quantity;
+            }
+            if quantity > DUST_QUANTITY {
+                lots.push_back(FuturesLot {
+                    side,
+                    quantity,
+                    price: fill.price,
+                    multiplier: fill.multiplier.unwrap_or(1.0),
+                });
+            }
+        }
+
+        if let Some(settlement) = settlement {
+            if let Some(lots) = positions.get_mut(&settlement.contract) {
+                let row = rows.entry((year, settlement.contract.clone())).or_default();
+                for lot in lots.drain(..) {
+                    row.realized += (settlement.price - lot.price) * lot.quantity * lot.multiplier * lot.side;
+                }
+            }
+        }
+    }
+    if TaxPeriod::tax_year(current_year, time_zone).end <= as_of {
+        mark_to_market(current_year, &mut positions, &mut rows);
+    }
+
+    rows.into_iter()
+        .map(|((year, contract), row)| Section1256Contract {
+            year,
+            contract,
+            realized: round_to_dollars(row.realized),
+            marked_to_market: round_to_dollars(row.marked_to_market),
+            fees: round_to_dollars(row.fees),
+            gain: round_to_dollars(row.realized + row.marked_to_market - row.fees),
+        })
+        .collect()
+}
+
+pub fn form_6781_by_year(contracts: &[Section1256Contract]) -> Vec<Form6781Year> {
+    let mut totals: BTreeMap<i32, f64> = BTreeMap::new();
+    for contract in contracts {
+        *totals.entry(contract.year).or_default() += contract.gain;
+    }
+    totals
+        .into_iter()
+        .map(|(year, total_gain)| {
+            let long_term_gain = round_to_dollars(total_gain * SECTION_1256_LONG_TERM_SHARE);
+            Form6781Year {
+                year,
+                total_gain: round_to_dollars(total_gain),
+                short_term_gain: round_to_dollars(total_gain - long_term_gain),
+                long_term_gain,
+            }
+        })
+        .collect()
+}
+
+pub fn save_section_1256_to_file(contracts: &[Section1256Contract], years: &[Form6781Year], contracts_file: &str, years_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(contracts_file)?);
+    for contract in contracts {
+        writer.serialize(contract)?;
+    }
+    writer.flush()?;
+
+    let mut writer = csv::Writer::from_writer(File::create(years_file)?);
+    for year in years {
+        writer.serialize(year)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
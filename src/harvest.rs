@@ -0,0 +1,244 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use self::chrono::Duration;
+use serde::Serialize;
+
+use account::{Account, Deposit};
+use tax::{capital_gain_type, round_to_dollars, ProcessingOptions, TaxEvent, CAPITAL_GAIN_TYPE_LONG};
+use tax_estimate::capital_loss_limit;
+use unrealized::DUST_QUANTITY;
+
+pub const LOSS_RULE_NONE: &str = "none";
+/// US rule: a loss is disallowed when the same asset is acquired within 30 days before or after the sale.
+pub const LOSS_RULE_WASH_SALE: &str = "wash-sale";
+/// Canadian rule: the same, when the asset acquired is still held 30 days after the sale.
+pub const LOSS_RULE_SUPERFICIAL_LOSS: &str = "superficial-loss";
+
+pub const LOSS_RULE_WINDOW_DAYS: i64 = 30;
+
+/// A lot that would be sold to harvest the losses of its asset.
+#[derive(Debug, Clone, Serialize)]
+pub struct HarvestLot {
+    pub asset: String,
+    /// Position of the lot in the order the accounting method sells the lots, starting at 1.
+    pub sell_order: usize,
+    pub buy_transaction_id: String,
+    pub buy_date: DateTime<Utc>,
+    pub quantity: f64,
+    pub cost_basis: f64,
+    pub market_value: f64,
+    pub gain: f64,
+    pub term: String,
+    /// Gain of selling this lot and every lot sold before it.
+    pub cumulative_gain: f64,
+    /// Why the loss of this lot could be disallowed, empty when it isn't.
+    pub loss_rule_warning: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HarvestAsset {
+    pub asset: String,
+    pub price: f64,
+    /// Quantity to sell, following the accounting method, to realize the largest loss.
+    pub quantity_to_sell: f64,
+    pub realizable_loss: f64,
+    pub short_term_loss: f64,
+    pub long_term_loss: f64,
+    /// Part of the loss of lots with a loss rule warning.
+    pub flagged_loss: f64,
+}
+
+/// How the harvested losses would offset the gains already realized in the year.
+#[derive(Debug, Default)]
+pub struct HarvestSummary {
+    pub realized_short_term_gain: f64,
+    pub realized_long_term_gain: f64,
+    pub realizable_loss: f64,
+    pub offset_gains: f64,
+    pub offset_ordinary_income: f64,
+    pub carried_forward: f64,
+}
+
+/// Finds, for every asset, how much to sell at `datetime` to realize the largest loss when the lots are sold in
+/// the order of the accounting method of `options` for the asset. Lots with a gain that are sold before the loss
+/// lots reduce the loss. The lots up to that point are returned with a warning when `loss_rule` could disallow
+/// their loss.
+pub fn find_harvest_opportunities(accounts: &HashMap<String, Account>, prices: &HashMap<String, f64>, datetime: DateTime<Utc>, options: &ProcessingOptions, loss_rule: &str) -> (Vec<HarvestLot>, Vec<HarvestAsset>) {
+    let mut lots = vec![];
+    let mut assets = vec![];
+
+    let mut names = accounts.keys().filter(|name| name.as_str() != "USD").collect::<Vec<&String>>();
+    names.sort();
+    for name in names {
+        let account = &accounts[name];
+        let price = match prices.get(name) {
+            Some(price) => *price,
+            None => {
+                if account.balance > DUST_QUANTITY {
+                    eprintln!("No price for {}, its losses are not harvested", name);
+                }
+                continue;
+            }
+        };
+
+        // Wallet elections don't apply, the losses are harvested from all the lots of the asset.
+        let open_lots = account
+            .open_lots_in_order(options.method_for(datetime, name, ""))
+            .into_iter()
+            .filter(|d| d.remaining_quantity > DUST_QUANTITY && d.datetime <= datetime)
+            .collect::<Vec<&Deposit>>();
+        let mut asset_lots = vec![];
+        let mut cumulative_gain = 0.0;
+        for (i, deposit) in open_lots.iter().enumerate() {
+            let cost_basis = round_to_dollars(deposit.remaining_cost_basis());
+            let market_value = round_to_dollars(deposit.remaining_quantity * price);
+            let gain = round_to_dollars(market_value - cost_basis);
+            cumulative_gain = round_to_dollars(cumulative_gain + gain);
+            asset_lots.push(HarvestLot {
+                asset: name.clone(),
+                sell_order: i + 1,
+                buy_transaction_id: deposit.transaction_id.clone(),
+                buy_date: deposit.datetime,
+                quantity: deposit.remaining_quantity,
+                cost_basis,
+                market_value,
+                gain,
+                term: capital_gain_type(deposit.datetime, datetime).to_string(),
+                cumulative_gain,
+                loss_rule_warning: String::new(),
+            });
+        }
+
+        let sold_lots = match asset_lots
+            .iter()
+            .enumerate()
+            .filter(|(_, lot)| lot.cumulative_gain < 0.0)
+            .min_by(|(_, lot1), (_, lot2)| lot1.cumulative_gain.total_cmp(&lot2.cumulative_gain))
+        {
+            Some((i, _)) => i + 1,
+            None => continue,
+        };
+        asset_lots.truncate(sold_lots);
+
+        let warning = loss_rule_warning(account, &open_lots[..sold_lots], &open_lots[sold_lots..], datetime, loss_rule);
+        for lot in asset_lots.iter_mut().filter(|lot| lot.gain < 0.0) {
+            lot.loss_rule_warning = warning.clone();
+        }
+
+        let mut asset = HarvestAsset {
+            asset: name.clone(),
+            price,
+            ..Default::default()
+        };
+        for lot in asset_lots.iter() {
+            asset.quantity_to_sell += lot.quantity;
+            asset.realizable_loss = round_to_dollars(asset.realizable_loss - lot.gain);
+            if lot.term == CAPITAL_GAIN_TYPE_LONG {
+                asset.long_term_loss = round_to_dollars(asset.long_term_loss - lot.gain);
+            } else {
+                asset.short_term_loss = round_to_dollars(asset.short_term_loss - lot.gain);
+            }
+            if lot.gain < 0.0 && !lot.loss_rule_warning.is_empty() {
+                asset.flagged_loss = round_to_dollars(asset.flagged_loss - lot.gain);
+            }
+        }
+        assets.push(asset);
+        lots.extend(asset_lots);
+    }
+
+    (lots, assets)
+}
+
+/// Looks for a lot of the asset, other than the `sold_lots`, acquired in the 30 days before the sale. For the
+/// superficial loss rule it must also still be held after the sale, i.e. be one of the `kept_lots`.
+/// Acquisitions in the 30 days after the sale are not known yet and are left to the reader.
+fn loss_rule_warning(account: &Account, sold_lots: &[&Deposit], kept_lots: &[&Deposit], datetime: DateTime<Utc>, loss_rule: &str) -> String {
+    if loss_rule == LOSS_RULE_NONE {
+        return String::new();
+    }
+
+    let window_start = datetime - Duration::days(LOSS_RULE_WINDOW_DAYS);
+    let in_window = |d: &Deposit| d.datetime >= window_start && d.datetime <= datetime;
+    let replacement = if loss_rule == LOSS_RULE_SUPERFICIAL_LOSS {
+        kept_lots.iter().find(|d| in_window(d)).cloned()
+    } else {
+        account
+            .deposits
+            .iter()
+            .find(|d| in_window(d) && d.remaining_quantity > DUST_QUANTITY && !sold_lots.iter().any(|sold| sold.transaction_id == d.transaction_id))
+    };
+
+    match replacement {
+        Some(deposit) => format!(
+            "{}: {} acquired by transaction {} on {}",
+            loss_rule,
+            account.name,
+            deposit.transaction_id,
+            deposit.datetime.format("%Y-%m-%d")
+        ),
+        None => String::new(),
+    }
+}
+
+/// Offsets the harvested losses against the net gains realized in the year, then against ordinary income
+/// up to the yearly limit. The rest would be carried forward.
+pub fn summarize_harvest(year_tax_events: &[TaxEvent], assets: &[HarvestAsset], filing_status: &str) -> HarvestSummary {
+    let mut summary = HarvestSummary::default();
+    for tax_event in year_tax_events {
+        if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+            summary.realized_long_term_gain += tax_event.gain;
+        } else {
+            summary.realized_short_term_gain += tax_event.gain;
+        }
+    }
+    summary.realized_short_term_gain = round_to_dollars(summary.realized_short_term_gain);
+    summary.realized_long_term_gain = round_to_dollars(summary.realized_long_term_gain);
+    summary.realizable_loss = round_to_dollars(assets.iter().map(|a| a.realizable_loss).sum());
+
+    let realized_gain = summary.realized_short_term_gain + summary.realized_long_term_gain;
+    summary.offset_gains = round_to_dollars(summary.realizable_loss.min(realized_gain.max(0.0)));
+    // A realized net loss already uses up part of the yearly limit.
+    let limit_left = (capital_loss_limit(filing_status) + realized_gain.min(0.0)).max(0.0);
+    summary.offset_ordinary_income = round_to_dollars((summary.realizable_loss - summary.offset_gains).min(limit_left));
+    summary.carried_forward = round_to_dollars(summary.realizable_loss - summary.offset_gains - summary.offset_ordinary_income);
+    summary
+}
+
+pub fn print_harvest_summary(assets: &[HarvestAsset], summary: &HarvestSummary) {
+    println!("{:<8} {:>16} {:>16} {:>16} {:>16} {:>16}", "Asset", "Quantity", "Loss", "Short term", "Long term", "Flagged");
+    for asset in assets {
+        println!(
+            "{:<8} {:>16.8} {:>16.2} {:>16.2} {:>16.2} {:>16.2}",
+            asset.asset, asset.quantity_to_sell, asset.realizable_loss, asset.short_term_loss, asset.long_term_loss, asset.flagged_loss
+        );
+    }
+    println!(
+        "Realized this year: short term {:.2}, long term {:.2}",
+        summary.realized_short_term_gain, summary.realized_long_term_gain
+    );
+    println!(
+        "Harvesting {:.2} of losses offsets {:.2} of gains and {:.2} of ordinary income, {:.2} would be carried forward.",
+        summary.realizable_loss, summary.offset_gains, summary.offset_ordinary_income, summary.carried_forward
+    );
+}
+
+pub fn save_harvest_to_file(lots: &[HarvestLot], assets: &[HarvestAsset], lots_file: &str, assets_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(lots_file)?);
+    for lot in lots {
+        writer.serialize(lot)?;
+    }
+    writer.flush()?;
+
+    let mut writer = csv::Writer::from_writer(File::create(assets_file)?);
+    for asset in assets {
+        writer.serialize(asset)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
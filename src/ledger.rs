@@ -0,0 +1,257 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Serialize;
+
+use account::Account;
+use tax::{is_non_taxable_conversion, is_transfer_between_own_wallets, process_transaction_carrying_lots, round_to_dollars, sort_transactions, ProcessingOptions, Transaction, WALLET_EXTERNAL, WALLET_INCOME, WALLET_NA};
+
+pub const LEDGER_ACCOUNT_FEES: &str = "Expenses:Fees";
+pub const LEDGER_ACCOUNT_REALIZED_GAINS: &str = "Income:Realized Gains";
+pub const LEDGER_ACCOUNT_INCOME: &str = "Income:Ordinary";
+pub const LEDGER_ACCOUNT_EXTERNAL: &str = "Equity:External";
+
+const FIAT_ASSET: &str = "USD";
+
+/// Debits and credits are in USD and considered equal when they differ by less than a cent.
+const BALANCE_TOLERANCE: f64 = 0.005;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalLine {
+    pub transaction_id: String,
+    pub datetime: DateTime<Utc>,
+    pub account: String,
+    pub debit: f64,
+    pub credit: f64,
+}
+
+#[derive(Debug)]
+pub struct JournalEntry {
+    pub transaction_id: String,
+    pub datetime: DateTime<Utc>,
+    pub lines: Vec<JournalLine>,
+}
+
+impl JournalEntry {
+    fn new(transaction: &Transaction) -> JournalEntry {
+        JournalEntry {
+            transaction_id: transaction.id.clone(),
+            datetime: transaction.datetime,
+            lines: vec![],
+        }
+    }
+
+    /// A negative debit is posted as a credit.
+    fn debit(&mut self, account: &str, amount: f64) {
+        if amount < 0.0 {
+            self.post(account, 0.0, -amount);
+        } else {
+            self.post(account, amount, 0.0);
+        }
+    }
+
+    /// A negative credit is posted as a debit.
+    fn credit(&mut self, account: &str, amount: f64) {
+        if amount < 0.0 {
+            self.post(account, -amount, 0.0);
+        } else {
+            self.post(account, 0.0, amount);
+        }
+    }
+
+    fn post(&mut self, account: &str, debit: f64, credit: f64) {
+        let debit = round_to_dollars(debit);
+        let credit = round_to_dollars(credit);
+        if debit == 0.0 && credit == 0.0 {
+            return;
+        }
+        self.lines.push(JournalLine {
+            transaction_id: self.transaction_id.clone(),
+            datetime: self.datetime,
+            account: account.to_string(),
+            debit,
+            credit,
+        });
+    }
+
+    pub fn debits(&self) -> f64 {
+        round_to_dollars(self.lines.iter().map(|l| l.debit).sum())
+    }
+
+    pub fn credits(&self) -> f64 {
+        round_to_dollars(self.lines.iter().map(|l| l.credit).sum())
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        (self.debits() - self.credits()).abs() < BALANCE_TOLERANCE
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrialBalanceRow {
+    pub account: String,
+    pub debit: f64,
+    pub credit: f64,
+    pub balance: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct GeneralLedger {
+    pub entries: Vec<JournalEntry>,
+}
+
+impl GeneralLedger {
+    /// Total debits and credits of every ledger account, sorted by account name.
+    pub fn trial_balance(&self) -> Vec<TrialBalanceRow> {
+        let mut totals: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+        for line in self.entries.iter().flat_map(|e| e.lines.iter()) {
+            let total = totals.entry(&line.account).or_insert((0.0, 0.0));
+            total.0 += line.debit;
+            total.1 += line.credit;
+        }
+
+        totals
+            .into_iter()
+            .map(|(account, (debit, credit))| TrialBalanceRow {
+                account: account.to_string(),
+                debit: round_to_dollars(debit),
+                credit: round_to_dollars(credit),
+                balance: round_to_dollars(debit - credit),
+            })
+            .collect()
+    }
+
+    pub fn total_debits(&self) -> f64 {
+        round_to_dollars(self.entries.iter().map(|e| e.debits()).sum())
+    }
+
+    pub fn total_credits(&self) -> f64 {
+        round_to_dollars(self.entries.iter().map(|e| e.credits()).sum())
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        (self.total_debits() - self.total_credits()).abs() < BALANCE_TOLERANCE
+    }
+
+    pub fn unbalanced_entries(&self) -> Vec<&JournalEntry> {
+        self.entries.iter().filter(|e| !e.is_balanced()).collect()
+    }
+
+    pub fn save_to_file(&self, out_file: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+        for line in self.entries.iter().flat_map(|e| e.lines.iter()) {
+            writer.serialize(line)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn asset_account(asset: &str) -> String {
+    if asset == FIAT_ASSET {
+        format!("Assets:Fiat:{}", asset)
+    } else {
+        format!("Assets:Crypto:{}", asset)
+    }
+}
+
+/// Replays the transactions like `calculate_capital_gains` and posts each one as a journal entry at cost:
+/// the received asset is debited with the `usd_value`, the spent asset is credited with the cost basis of the
+/// lots it consumed and the difference goes to realized gains. Value coming from an external wallet is equity
+/// and value coming from the income wallet is ordinary income. Fees are an expense paid in their `fee_asset`.
+/// A migration or a non-taxable conversion moves the cost basis of the lots it carries over, and the received
+/// asset is debited with the basis of the lots it received. Any other difference is left in the entry, which is
+/// then reported as unbalanced.
+pub fn post_transactions(transactions: &mut [Transaction], mut accounts: HashMap<String, Account>, options: &ProcessingOptions) -> GeneralLedger {
+    sort_transactions(transactions);
+
+    let mut ledger = GeneralLedger::default();
+    for transaction in transactions.iter() {
+        let carries_lots = transaction.is_migration() || is_non_taxable_conversion(transaction, options);
+        let (tax_events, carried_cost_basis) = process_transaction_carrying_lots(transaction, &mut accounts, options);
+        let mut entry = JournalEntry::new(transaction);
+
+        if carries_lots {
+            let received_cost_basis = carried_cost_basis.carried + carried_cost_basis.uncovered;
+            entry.debit(&asset_account(&transaction.destination_asset), received_cost_basis);
+            entry.credit(&asset_account(&transaction.origin_asset), carried_cost_basis.carried);
+        } else {
+            entry.debit(&asset_account(&transaction.destination_asset), transaction.usd_value_or_zero());
+        }
+
+        let matched_value = if carries_lots {
+            // Posted above, at cost. What the lots held don't cover is left unbalanced.
+            transaction.usd_value_or_zero()
+        } else if transaction.origin_wallet == WALLET_INCOME {
+            0.0
+        } else if is_transfer_between_own_wallets(transaction) {
+            // A transfer between own wallets keeps its lots.
+            entry.credit(&asset_account(&transaction.origin_asset), transaction.usd_value_or_zero());
+            transaction.usd_value_or_zero()
+        } else if transaction.origin_wallet == WALLET_NA {
+            0.0
+        } else if transaction.origin_asset == FIAT_ASSET {
+            entry.credit(&asset_account(FIAT_ASSET), transaction.origin_quantity);
+            transaction.origin_quantity
+        } else {
+            // The proceeds are not summed from the tax events, whose per lot rounding would unbalance the entry.
+            let sold_quantity: f64 = tax_events.iter().map(|e| e.quantity).sum();
+            let proceeds = transaction.usd_value_or_zero() * (sold_quantity / transaction.origin_quantity);
+            let cost_basis = round_to_dollars(tax_events.iter().map(|e| e.cost_basis).sum());
+            entry.credit(&asset_account(&transaction.origin_asset), cost_basis);
+            entry.credit(LEDGER_ACCOUNT_REALIZED_GAINS, proceeds - cost_basis);
+            proceeds
+        };
+
+        let unmatched_value = transaction.usd_value_or_zero() - matched_value;
+        if transaction.origin_wallet == WALLET_INCOME {
+            entry.credit(LEDGER_ACCOUNT_INCOME, unmatched_value);
+        } else if transaction.origin_wallet == WALLET_EXTERNAL || transaction.origin_wallet == WALLET_NA {
+            entry.credit(LEDGER_ACCOUNT_EXTERNAL, unmatched_value);
+        }
+
+        if let Some(fee) = transaction.usd_fee.filter(|fee| *fee > 0.0) {
+            entry.debit(LEDGER_ACCOUNT_FEES, fee);
+            entry.credit(&asset_account(transaction.fee_asset.as_deref().unwrap_or(FIAT_ASSET)), fee);
+        }
+
+        ledger.entries.push(entry);
+    }
+
+    ledger
+}
+
+/// Prints the trial balance followed by the unbalanced entries.
+pub fn print_trial_balance(ledger: &GeneralLedger) {
+    println!("{:<32} {:>16} {:>16} {:>16}", "Account", "Debit", "Credit", "Balance");
+    for row in ledger.trial_balance() {
+        println!("{:<32} {:>16.2} {:>16.2} {:>16.2}", row.account, row.debit, row.credit, row.balance);
+    }
+    println!("{:<32} {:>16.2} {:>16.2}", "Total", ledger.total_debits(), ledger.total_credits());
+    if !ledger.is_balanced() {
+        println!("Total debits and credits differ by {:.2}", ledger.total_debits() - ledger.total_credits());
+    }
+
+    let unbalanced_entries = ledger.unbalanced_entries();
+    if unbalanced_entries.is_empty() {
+        println!("All {} entries are balanced.", ledger.entries.len());
+    } else {
+        println!("{} unbalanced entries:", unbalanced_entries.len());
+        println!("{:<16} {:<26} {:>16} {:>16} {:>16}", "Transaction", "Datetime", "Debits", "Credits", "Difference");
+        for entry in unbalanced_entries {
+            println!(
+                "{:<16} {:<26} {:>16.2} {:>16.2} {:>16.2}",
+                entry.transaction_id,
+                entry.datetime.to_rfc3339(),
+                entry.debits(),
+                entry.credits(),
+                entry.debits() - entry.credits()
+            );
+        }
+    }
+}
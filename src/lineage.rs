@@ -0,0 +1,101 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Serialize;
+
+use account::{Account, Deposit};
+use tax::TaxEvent;
+
+/// One lot in the chain of lots that led to a tax event.
+#[derive(Debug, Clone, Serialize)]
+pub struct LineageStep {
+    /// Position of the tax event in the list of tax events.
+    pub tax_event: usize,
+    /// 0 for the sold lot, 1 for the lots spent to acquire it and so on.
+    pub depth: usize,
+    pub transaction_id: String,
+    /// The lot that was acquired by spending this one, empty for the sold lot.
+    pub spent_for: String,
+    pub asset: String,
+    pub datetime: DateTime<Utc>,
+    pub quantity: f64,
+    pub usd_value: f64,
+}
+
+/// Every lot of the accounts by its asset and the id of the transaction that created it: lots carried over to
+/// another asset, e.g. by a wrap, keep their id. Of the lots sharing an asset and an id, e.g. the parts of a lot
+/// split between wallets, the first one is kept: it records the whole acquisition.
+pub struct LotIndex<'a> {
+    lots: HashMap<(&'a str, &'a str), &'a Deposit>,
+    /// The first asset, by name, with a lot of each id, for the parents whose asset is not known.
+    assets: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> LotIndex<'a> {
+    pub fn new(accounts: &'a HashMap<String, Account>) -> LotIndex<'a> {
+        let mut lots = HashMap::new();
+        let mut assets = HashMap::new();
+        let mut names = accounts.keys().collect::<Vec<&String>>();
+        names.sort();
+        for asset in names {
+            for deposit in accounts[asset].deposits.iter() {
+                lots.entry((asset.as_str(), deposit.transaction_id.as_str())).or_insert(deposit);
+                assets.entry(deposit.transaction_id.as_str()).or_insert(asset.as_str());
+            }
+        }
+        LotIndex { lots, assets }
+    }
+
+    /// Walks back from the lot sold by `tax_event` through the lots spent to acquire it,
+    /// down to the lots bought with USD or received from outside.
+    pub fn lineage(&self, tax_event_index: usize, tax_event: &TaxEvent) -> Vec<LineageStep> {
+        let mut steps = vec![];
+        let mut pending = vec![(0, Some(tax_event.asset.as_str()), tax_event.buy_transaction_id.as_str(), "")];
+
+        while let Some((depth, asset, transaction_id, spent_for)) = pending.pop() {
+            let asset = match asset.or_else(|| self.assets.get(transaction_id).cloned()) {
+                Some(asset) => asset,
+                None => continue,
+            };
+            let deposit = match self.lots.get(&(asset, transaction_id)) {
+                Some(deposit) => *deposit,
+                None => continue,
+            };
+            steps.push(LineageStep {
+                tax_event: tax_event_index,
+                depth,
+                transaction_id: transaction_id.to_string(),
+                spent_for: spent_for.to_string(),
+                asset: asset.to_string(),
+                datetime: deposit.datetime,
+                quantity: deposit.quantity,
+                usd_value: deposit.usd_value,
+            });
+            for parent in deposit.parents.iter().rev() {
+                // Parents are always older, a lot listing itself would be a data error.
+                if parent != transaction_id {
+                    pending.push((depth + 1, deposit.parent_asset.as_deref(), parent.as_str(), transaction_id));
+                }
+            }
+        }
+
+        steps
+    }
+}
+
+pub fn save_lineage_to_file(tax_events: &[TaxEvent], accounts: &HashMap<String, Account>, out_file: &str) -> Result<(), Box<dyn Error>> {
+    let lot_index = LotIndex::new(accounts);
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for (index, tax_event) in tax_events.iter().enumerate() {
+        for step in lot_index.lineage(index, tax_event) {
+            writer.serialize(step)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
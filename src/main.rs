@@ -11,19 +11,58 @@ extern crate chrono;
 
 
 mod account;
+mod compare;
 mod conversions;
+mod equivalence;
+mod futures;
+mod harvest;
+mod ledger;
+mod lineage;
+mod margin;
+mod method_schedule;
+mod period;
+mod planner;
+mod prices;
+mod reconcile;
+mod schedule_d;
+mod simulate;
+mod streaming;
 mod tax;
+mod tax_estimate;
+mod unrealized;
+mod wallet_allocation;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::fs::File;
+use std::fs::{self, File};
 use std::hash::Hash;
 use std::io;
-use chrono::Duration;
+use std::path::Path;
+use chrono::{FixedOffset, NaiveDate};
+use chrono::{DateTime, Utc};
 use clap::{App, Arg, ArgMatches};
+use compare::*;
 use conversions::*;
+use equivalence::*;
+use futures::*;
+use harvest::*;
+use ledger::*;
+use lineage::*;
+use margin::*;
+use method_schedule::*;
 use tax::*;
-use account::{Account, TAX_ACCOUNTING_METHOD_LIFO};
+use tax_estimate::*;
+use unrealized::*;
+use wallet_allocation::*;
+use account::{Account, Deposit, TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_HIFO, TAX_ACCOUNTING_METHOD_LIFO};
+use serde::Deserialize;
+use period::*;
+use planner::*;
+use prices::*;
+use reconcile::*;
+use schedule_d::*;
+use simulate::*;
+use streaming::*;
 
 fn read_arguments<'a>() -> ArgMatches<'a> {
     App::new("Pine Tree Tax")
@@ -36,7 +75,7 @@ fn read_arguments<'a>() -> ArgMatches<'a> {
         )
         .arg(
             Arg::with_name("tax-accounting-method")
-                .help("tax accounting method: FIFO (First-In-First-Out), LIFO (Last-In-First-Out) or HIFO (High-In-First-Out)")
+                .help("tax accounting method: FIFO (First-In-First-Out), LIFO (Last-In-First-Out), HIFO (High-In-First-Out) or SPECIFIC_ID (the lots of --lot-designations, then FIFO)")
                 .short("m")
                 .required(false)
                 .takes_value(true)
@@ -51,6 +90,316 @@ fn read_arguments<'a>() -> ArgMatches<'a> {
                 .takes_value(true)
                 .value_name("FILE_FORMAT"),
         )
+        .arg(
+            Arg::with_name("prices")
+                .short("p")
+                .long("prices")
+                .required(false)
+                .help("Historical price .csv file used to value transactions. Can be given multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PRICES_FILE"),
+        )
+        .arg(
+            Arg::with_name("price-interpolation")
+                .long("price-interpolation")
+                .required(false)
+                .help("How to price a timestamp between two price points: previous, nearest or linear.")
+                .takes_value(true)
+                .possible_values(&[PRICE_INTERPOLATION_PREVIOUS, PRICE_INTERPOLATION_NEAREST, PRICE_INTERPOLATION_LINEAR])
+                .value_name("INTERPOLATION"),
+        )
+        .arg(
+            Arg::with_name("opening-accounts")
+                .short("o")
+                .long("opening-accounts")
+                .required(false)
+                .help("Accounts .csv file saved with -a for the previous year. Its remaining lots are the opening balances.")
+                .takes_value(true)
+                .value_name("ACCOUNTS_FILE"),
+        )
+        .arg(
+            Arg::with_name("tax-year")
+                .long("tax-year")
+                .required(false)
+                .help("Only report the sales and income of this year. All transactions are still used to build the lots.")
+                .takes_value(true)
+                .conflicts_with_all(&["from", "to"])
+                .value_name("YEAR"),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .required(false)
+                .help("Only report the sales and income from this date (YYYY-MM-DD).")
+                .takes_value(true)
+                .value_name("DATE"),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .required(false)
+                .help("Only report the sales and income up to and including this date (YYYY-MM-DD).")
+                .takes_value(true)
+                .value_name("DATE"),
+        )
+        .arg(
+            Arg::with_name("utc-offset")
+                .long("utc-offset")
+                .required(false)
+                .help("Fixed UTC offset in which the tax year and date boundaries are evaluated, e.g. -05:00. Defaults to UTC. Not a time zone: daylight saving time is not applied, so for a zone that observes it the boundaries that fall in summer time are off by an hour.")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_name("OFFSET"),
+        )
+        .arg(
+            Arg::with_name("by-year")
+                .short("y")
+                .long("by-year")
+                .required(false)
+                .help("Save a complete set of reports for every tax year in a sub directory of this directory.")
+                .takes_value(true)
+                .conflicts_with_all(&["tax-year", "from", "to"])
+                .value_name("OUTPUT_DIR"),
+        )
+        .arg(
+            Arg::with_name("unrealized")
+                .short("u")
+                .long("unrealized")
+                .required(false)
+                .help("Save the unrealized gains of the lots held at this date (YYYY-MM-DD or RFC 3339).")
+                .takes_value(true)
+                .value_name("DATE"),
+        )
+        .arg(
+            Arg::with_name("price-snapshot")
+                .long("price-snapshot")
+                .required(false)
+                .help("asset,price .csv file used to value the lots. Without it the prices loaded with -p are used.")
+                .takes_value(true)
+                .value_name("PRICES_FILE"),
+        )
+        .arg(
+            Arg::with_name("harvest")
+                .long("harvest")
+                .required(false)
+                .help("Save the lots that could be sold at this date (YYYY-MM-DD or RFC 3339) to realize a loss, and the gains of the year it would offset.")
+                .takes_value(true)
+                .value_name("DATE"),
+        )
+        .arg(
+            Arg::with_name("loss-rule")
+                .long("loss-rule")
+                .requires("harvest")
+                .takes_value(true)
+                .possible_values(&[LOSS_RULE_NONE, LOSS_RULE_WASH_SALE, LOSS_RULE_SUPERFICIAL_LOSS])
+                .value_name("RULE")
+                .help("Flag the harvested losses that this rule could disallow. Default: none."),
+        )
+        .arg(
+            Arg::with_name("raise-cash")
+                .long("raise-cash")
+                .requires("raise-cash-by")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .help("Plan the sales of lots that raise this amount of USD for the least estimated tax."),
+        )
+        .arg(
+            Arg::with_name("raise-cash-by")
+                .long("raise-cash-by")
+                .requires("raise-cash")
+                .takes_value(true)
+                .value_name("DATE")
+                .help("Date of the sales planned with --raise-cash (YYYY-MM-DD or RFC 3339)."),
+        )
+        .arg(
+            Arg::with_name("l")
+                .short("l")
+                .long("general-ledger")
+                .help("Print the trial balance of the general ledger and save the journal entries in a .csv file."),
+        )
+        .arg(
+            Arg::with_name("reconcile")
+                .short("r")
+                .long("reconcile")
+                .required(false)
+                .help("Compare the balance of every wallet with a wallet,asset,datetime,quantity .csv file of reported balances.")
+                .takes_value(true)
+                .value_name("BALANCES_FILE"),
+        )
+        .arg(
+            Arg::with_name("same-time-deposits-spendable")
+                .long("same-time-deposits-spendable")
+                .help("Allow selling coins deposited at the same datetime as the sale."),
+        )
+        .arg(
+            Arg::with_name("method-schedule")
+                .long("method-schedule")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read tax accounting method elections by date range, asset and wallet (method,from,to,asset,wallet) from a .csv file. They override -m."),
+        )
+        .arg(
+            Arg::with_name("lot-designations")
+                .long("lot-designations")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read the lots sold by the SPECIFIC_ID sales (sell_transaction_id,buy_transaction_id,quantity) from a .csv file."),
+        )
+        .arg(
+            Arg::with_name("kraken-ledger")
+                .long("kraken-ledger")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("convert-from")
+                .help("Read the rollover fees of the margin positions from the Kraken ledgers export when converting Kraken trades."),
+        )
+        .arg(
+            Arg::with_name("margin-trades")
+                .long("margin-trades")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read margin trades and rollover fees (id,datetime,asset,type,quantity,usd_value,usd_fee) from a .csv file and save their gains separately."),
+        )
+        .arg(
+            Arg::with_name("futures")
+                .long("futures")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read regulated futures fills (id,datetime,contract,type,quantity,price,multiplier,usd_fee) from a .csv file and save their Section 1256 gains, marked to market at every year end."),
+        )
+        .arg(
+            Arg::with_name("futures-settlements")
+                .long("futures-settlements")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("futures")
+                .help("Read the settlement prices of the futures contracts (contract,datetime,price,final) from a .csv file."),
+        )
+        .arg(
+            Arg::with_name("equivalence-rules")
+                .long("equivalence-rules")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read the asset pairs whose conversions, e.g. wrapping ETH into WETH, carry the lots over without a tax event (asset,equivalent_asset,non_taxable) from a .csv file."),
+        )
+        .arg(
+            Arg::with_name("allocate-lots")
+                .long("allocate-lots")
+                .takes_value(true)
+                .value_name("DATE")
+                .conflicts_with("streaming")
+                .help("Allocate the lots held at this date (YYYY-MM-DD or RFC 3339) to the wallets holding them, save the allocation in a .csv file and track the lots per wallet from then on."),
+        )
+        .arg(
+            Arg::with_name("lot-allocation")
+                .long("lot-allocation")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("allocate-lots")
+                .help("Use the lot allocation saved by a previous --allocate-lots run instead of proposing one."),
+        )
+        .arg(
+            Arg::with_name("lineage")
+                .long("lineage")
+                .help("Save the chain of lots behind every tax event, back to the USD purchase, in a .csv file."),
+        )
+        .arg(
+            Arg::with_name("compare-methods")
+                .long("compare-methods")
+                .conflicts_with_all(&["by-year", "tax-year", "from", "to"])
+                .help("Compare the gains and the estimated tax of every year with each accounting method."),
+        )
+        .arg(
+            Arg::with_name("short-term-rate")
+                .long("short-term-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .help("Flat tax rate of short term gains used by --raise-cash, and by --compare-methods and --simulate without --tax-brackets, e.g. 0.24. Default: 0.24."),
+        )
+        .arg(
+            Arg::with_name("long-term-rate")
+                .long("long-term-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .help("Flat tax rate of long term gains used by --raise-cash, and by --compare-methods and --simulate without --tax-brackets, e.g. 0.15. Default: 0.15."),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .takes_value(true)
+                .value_name("TRANSACTIONS_FILE")
+                .conflicts_with_all(&["compare-methods", "by-year", "streaming"])
+                .help("Apply hypothetical transactions after the real ones and print their tax events and tax, without saving any report."),
+        )
+        .arg(
+            Arg::with_name("simulate-method")
+                .long("simulate-method")
+                .requires("simulate")
+                .takes_value(true)
+                .possible_values(&[TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_LIFO, TAX_ACCOUNTING_METHOD_HIFO])
+                .value_name("METHOD")
+                .help("Accounting method used for the hypothetical transactions. Default: the -m method."),
+        )
+        .arg(
+            Arg::with_name("tax-brackets")
+                .long("tax-brackets")
+                .takes_value(true)
+                .value_name("BRACKETS_FILE")
+                .help("year,filing_status,kind,threshold,rate .csv file of tax brackets used to estimate the tax of every year."),
+        )
+        .arg(
+            Arg::with_name("filing-status")
+                .long("filing-status")
+                .takes_value(true)
+                .possible_values(&[FILING_STATUS_SINGLE, FILING_STATUS_MARRIED_FILING_JOINTLY, FILING_STATUS_MARRIED_FILING_SEPARATELY, FILING_STATUS_HEAD_OF_HOUSEHOLD])
+                .value_name("STATUS")
+                .help("Filing status used to select the tax brackets and the capital loss limit. Default: single."),
+        )
+        .arg(
+            Arg::with_name("loss-carryforward")
+                .long("loss-carryforward")
+                .takes_value(true)
+                .value_name("CARRYFORWARD_FILE")
+                .help("year,short_term,long_term .csv file of the capital losses carried forward from earlier years, e.g. a _loss_carryforward.csv file of the previous year."),
+        )
+        .arg(
+            Arg::with_name("other-income")
+                .long("other-income")
+                .requires("tax-brackets")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .help("Taxable ordinary income from other sources, after deductions. Default: 0."),
+        )
+        .arg(
+            Arg::with_name("deductions")
+                .long("deductions")
+                .requires("tax-brackets")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .help("Deductions taken from the income, e.g. the standard deduction, added back to the taxable income for the net investment income tax threshold. Default: 0."),
+        )
+        .arg(
+            Arg::with_name("streaming")
+                .long("streaming")
+                .conflicts_with_all(&["by-year", "compare-methods", "unrealized", "l", "reconcile", "lineage", "e"])
+                .help("Process the transactions one at a time and write the tax events as they are produced, for files too large for memory."),
+        )
+        .arg(
+            Arg::with_name("presorted")
+                .long("presorted")
+                .requires("streaming")
+                .help("The transactions file is already sorted by datetime, skip the external sort."),
+        )
+        .arg(
+            Arg::with_name("chunk-size")
+                .long("chunk-size")
+                .requires("streaming")
+                .takes_value(true)
+                .value_name("TRANSACTIONS")
+                .help("Number of transactions sorted in memory at a time by the external sort. Default: 100000."),
+        )
         .arg(
             Arg::with_name("a")
                 .short("a")
@@ -63,6 +412,12 @@ fn read_arguments<'a>() -> ArgMatches<'a> {
                 .long("save-transactions-tax-events")
                 .help("Save the transactions and the tax events in a .csv file."),
         )
+        .arg(
+            Arg::with_name("aggregate-tax-events")
+                .long("aggregate-tax-events")
+                .requires("e")
+                .help("Save a single row per transaction with the totals of its tax events instead of a row per lot."),
+        )
         .arg(
             Arg::with_name("output-file")
                 .help("Output file.")
@@ -75,6 +430,8 @@ fn read_arguments<'a>() -> ArgMatches<'a> {
 fn main() {
     let cli_args = read_arguments();
     let input_file = cli_args.value_of("INPUT_FILE").unwrap();
+    let price_interpolation = cli_args.value_of("price-interpolation").unwrap_or(PRICE_INTERPOLATION_PREVIOUS);
+    let prices = cli_args.values_of("prices").map(|files| read_prices(files.collect()).expect("read prices"));
 
     if let Some(convert_from_another_format) = cli_args.value_of("convert-from") {
         let output_file = cli_args
@@ -85,6 +442,10 @@ fn main() {
             process_kraken_transactions(
                 input_file,
                 &(convert_from_another_format.to_owned() + output_file),
+                &(convert_from_another_format.to_owned() + "_margin" + output_file),
+                cli_args.value_of("kraken-ledger"),
+                prices.as_ref(),
+                price_interpolation,
             );
         }
 
@@ -92,16 +453,167 @@ fn main() {
             process_bittrex_transactions(
                 input_file,
                 &(convert_from_another_format.to_owned() + output_file),
+                prices.as_ref(),
+                price_interpolation,
             );
         }
     } else {
         let tax_accounting_method = cli_args.value_of("tax-accounting-method").unwrap_or(TAX_ACCOUNTING_METHOD_LIFO);
+        let mut options = ProcessingOptions::new(tax_accounting_method);
+        options.same_time_deposits_spendable = cli_args.is_present("same-time-deposits-spendable");
+        if let Some(schedule_file) = cli_args.value_of("method-schedule") {
+            let time_zone = read_utc_offset(&cli_args);
+            options.method_schedule = Some(read_method_schedule(schedule_file, &time_zone).expect("read method schedule"));
+        }
+        if let Some(designations_file) = cli_args.value_of("lot-designations") {
+            options.lot_designations = read_lot_designations(designations_file).expect("read lot designations");
+        }
+        if let Some(rules_file) = cli_args.value_of("equivalence-rules") {
+            options.equivalence_rules = Some(read_equivalence_rules(rules_file).expect("read equivalence rules"));
+        }
         let output_file = cli_args.value_of("output-file").unwrap_or("transactions");
         let output_accounts = cli_args.occurrences_of("a");
         let output_transactions_and_tax_events = cli_args.occurrences_of("e");
 
+        if cli_args.is_present("streaming") {
+            process_stream(&cli_args, input_file, output_file, &options, prices.as_ref(), price_interpolation, output_accounts > 0).expect("process transactions stream");
+            return;
+        }
+
         let mut transactions = read_transactions(input_file).expect("read transactions");
-        let (tax_events, accounts) = calculate_capital_gains(&mut transactions, tax_accounting_method);
+        if let Some(prices) = &prices {
+            for id in fill_missing_usd_values(&mut transactions, prices, price_interpolation) {
+                eprintln!("transaction {}: no usd_value and no price available", id);
+            }
+            for warning in validate_usd_values(&transactions, prices, price_interpolation, USD_VALUE_TOLERANCE) {
+                eprintln!("{}", warning);
+            }
+        }
+        for conflict in same_timestamp_conflicts(&transactions) {
+            eprintln!("{}", conflict);
+        }
+        let mut accounts = default_accounts();
+        if let Some(opening_accounts_file) = cli_args.value_of("opening-accounts") {
+            accounts.extend(read_accounts_from_file(opening_accounts_file).expect("read opening accounts"));
+        }
+        if let Some(date) = cli_args.value_of("allocate-lots") {
+            let cutoff = parse_price_datetime(date).expect("lot allocation date");
+            let allocations = match cli_args.value_of("lot-allocation") {
+                Some(allocation_file) => read_lot_allocation(allocation_file).expect("read lot allocation"),
+                None => {
+                    let mut held_transactions = transactions.iter().filter(|t| t.datetime < cutoff).cloned().collect::<Vec<Transaction>>();
+                    let (_, held_accounts) = calculate_capital_gains_with_accounts(&mut held_transactions, accounts.clone(), &options);
+                    let (allocations, warnings) = propose_lot_allocation(&held_accounts, &wallet_balances(&held_transactions), &options.tax_accounting_method);
+                    for warning in warnings {
+                        eprintln!("{}", warning);
+                    }
+                    allocations
+                }
+            };
+            save_lot_allocation_to_file(&allocations, &(output_file.to_owned() + "_lot_allocation.csv")).expect("save lot allocation file");
+            options.wallet_allocation = Some(WalletAllocation::new(cutoff, allocations));
+        }
+        if let Some(statements_file) = cli_args.value_of("reconcile") {
+            let statements = read_balance_statements(statements_file).expect("read balance statements");
+            let rows = reconcile(&transactions, &accounts, &statements);
+            save_reconciliation_to_file(&rows, &(output_file.to_owned() + "_reconciliation.csv")).expect("save reconciliation file");
+            for row in rows.iter().filter(|row| !row.is_match()) {
+                println!(
+                    "{} {} at {}: computed {} but reported {}. Transactions to review: {}",
+                    row.wallet, row.asset, row.datetime, row.computed_quantity, row.reported_quantity, row.transactions_to_review
+                );
+            }
+        }
+
+        if cli_args.is_present("l") {
+            let ledger = post_transactions(&mut transactions.clone(), accounts.clone(), &options);
+            ledger.save_to_file(&(output_file.to_owned() + "_general_ledger.csv")).expect("save general ledger file");
+            print_trial_balance(&ledger);
+        }
+
+        if let Some(date) = cli_args.value_of("unrealized") {
+            let datetime = parse_price_datetime(date).expect("unrealized gains date");
+            let mut held_transactions = transactions.iter().filter(|t| t.datetime <= datetime).cloned().collect::<Vec<Transaction>>();
+            let (_, held_accounts) = calculate_capital_gains_with_accounts(&mut held_transactions, accounts.clone(), &options);
+            let snapshot = read_price_snapshot_at(&cli_args, prices.as_ref(), &held_accounts, datetime, price_interpolation);
+            let (lots, assets) = calculate_unrealized_gains(&held_accounts, &snapshot, datetime);
+            save_unrealized_gains_to_file(&lots, &assets, &(output_file.to_owned() + "_unrealized_lots.csv"), &(output_file.to_owned() + "_unrealized_assets.csv"))
+                .expect("save unrealized gains files");
+        }
+
+        if let Some(date) = cli_args.value_of("harvest") {
+            let datetime = parse_price_datetime(date).expect("harvest date");
+            let time_zone = read_utc_offset(&cli_args);
+            let mut held_transactions = transactions.iter().filter(|t| t.datetime <= datetime).cloned().collect::<Vec<Transaction>>();
+            let (mut year_tax_events, held_accounts) = calculate_capital_gains_with_accounts(&mut held_transactions, accounts.clone(), &options);
+            year_tax_events.retain(|e| tax_year_of(e.sell_date, &time_zone) == tax_year_of(datetime, &time_zone));
+            let snapshot = read_price_snapshot_at(&cli_args, prices.as_ref(), &held_accounts, datetime, price_interpolation);
+            let loss_rule = cli_args.value_of("loss-rule").unwrap_or(LOSS_RULE_NONE);
+            let (lots, assets) = find_harvest_opportunities(&held_accounts, &snapshot, datetime, &options, loss_rule);
+            save_harvest_to_file(&lots, &assets, &(output_file.to_owned() + "_harvest_lots.csv"), &(output_file.to_owned() + "_harvest_assets.csv"))
+                .expect("save harvest files");
+            print_harvest_summary(&assets, &summarize_harvest(&year_tax_events, &assets, cli_args.value_of("filing-status").unwrap_or(FILING_STATUS_SINGLE)));
+        }
+
+        if cli_args.is_present("compare-methods") {
+            let time_zone = read_utc_offset(&cli_args);
+            let filing_status = cli_args.value_of("filing-status").unwrap_or(FILING_STATUS_SINGLE);
+            let first_year = transactions.iter().map(|t| tax_year_of(t.datetime, &time_zone)).min();
+            let opening_carryforward = read_opening_carryforward(&cli_args, first_year);
+            let rows = compare_accounting_methods(&transactions, &accounts, &options, opening_carryforward.as_ref(), filing_status, &time_zone, read_tax_estimator(&cli_args).as_ref());
+            save_method_comparison_to_file(&rows, &(output_file.to_owned() + "_method_comparison.csv")).expect("save method comparison file");
+            print_method_comparison(&rows);
+            return;
+        }
+
+        if let Some(amount) = cli_args.value_of("raise-cash") {
+            let target = amount.parse::<f64>().expect("an amount of USD to raise");
+            let datetime = parse_price_datetime(cli_args.value_of("raise-cash-by").unwrap()).expect("raise cash date");
+            let mut held_transactions = transactions.iter().filter(|t| t.datetime <= datetime).cloned().collect::<Vec<Transaction>>();
+            let (held_tax_events, held_accounts) = calculate_capital_gains_with_accounts(&mut held_transactions, accounts.clone(), &options);
+            let snapshot = read_price_snapshot_at(&cli_args, prices.as_ref(), &held_accounts, datetime, price_interpolation);
+            let time_zone = read_utc_offset(&cli_args);
+            let realized = RealizedYear::new(tax_year_of(datetime, &time_zone), &held_tax_events, &calculate_income(&held_transactions), &time_zone);
+            let plan = plan_cash_raise(&held_accounts, &snapshot, datetime, target, &realized, &options, read_tax_estimator(&cli_args).as_ref());
+            save_cash_plan_to_file(&plan, &(output_file.to_owned() + "_cash_plan.csv")).expect("save cash plan file");
+            print_cash_plan(&plan);
+            return;
+        }
+
+        if let Some(simulate_file) = cli_args.value_of("simulate") {
+            let time_zone = read_utc_offset(&cli_args);
+            let (real_tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions, accounts, &options);
+            let income = calculate_income(&transactions);
+            let mut hypothetical_transactions = read_transactions(simulate_file).expect("read hypothetical transactions");
+            let mut simulate_options = options.clone();
+            if let Some(method) = cli_args.value_of("simulate-method") {
+                simulate_options.tax_accounting_method = method.to_string();
+                simulate_options.method_schedule = None;
+            }
+            let history_end = transactions.iter().map(|t| t.datetime).max();
+            let simulation = simulate_transactions(&accounts, history_end, &mut hypothetical_transactions, &simulate_options);
+            let impact = simulated_tax_impact(&real_tax_events, &income, &simulation.tax_events, read_tax_estimator(&cli_args).as_ref(), &time_zone);
+            print_simulation(&simulation, &impact);
+            return;
+        }
+
+        if let Some(output_dir) = cli_args.value_of("by-year") {
+            let time_zone = read_utc_offset(&cli_args);
+            let (tax_events, year_end_accounts) = calculate_capital_gains_by_year(&mut transactions, accounts, &options, &time_zone);
+            let income = calculate_income(&transactions);
+            save_reports_by_year(output_dir, &tax_events, &income, &year_end_accounts, &time_zone).expect("save reports by year");
+            return;
+        }
+
+        let (mut tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions, accounts, &options);
+        let mut income = calculate_income(&transactions);
+
+        if let Some(period) = read_tax_period(&cli_args) {
+            tax_events.retain(|e| period.contains(e.sell_date));
+            income.retain(|i| period.contains(i.datetime));
+            transactions.retain(|t| period.contains(t.datetime));
+        }
+
         save_to_file(
             &tax_events,
             &(output_file.to_owned() + "_long_gains.csv"),
@@ -113,16 +625,195 @@ fn main() {
             CAPITAL_GAIN_TYPE_SHORT,
         );
 
+        if !income.is_empty() {
+            save_income_to_file(&income, &(output_file.to_owned() + "_income.csv")).expect("save income file");
+        }
+
+        if let Some(margin_file) = cli_args.value_of("margin-trades") {
+            let mut margin_transactions = read_margin_transactions(margin_file).expect("read margin trades");
+            let (mut margin_gains, positions) = calculate_margin_gains(&mut margin_transactions);
+            if let Some(period) = read_tax_period(&cli_args) {
+                margin_gains.retain(|g| period.contains(g.close_date));
+            }
+            save_margin_gains_to_file(&margin_gains, &(output_file.to_owned() + "_margin_gains.csv")).expect("save margin gains file");
+            save_margin_positions_to_file(&positions, &(output_file.to_owned() + "_margin_positions.csv")).expect("save margin positions file");
+        }
+
+        if let Some(futures_file) = cli_args.value_of("futures") {
+            let time_zone = read_utc_offset(&cli_args);
+            let fills = read_futures_fills(futures_file).expect("read futures fills");
+            let settlements = match cli_args.value_of("futures-settlements") {
+                Some(settlements_file) => read_futures_settlements(settlements_file).expect("read futures settlements"),
+                None => vec![],
+            };
+            // The end of the -y or --to period, or else today, tells whether the last year is over.
+            let as_of = read_tax_period(&cli_args).map(|period| period.end).filter(|end| *end < DateTime::<Utc>::MAX_UTC).unwrap_or_else(Utc::now);
+            let contracts = calculate_section_1256(&fills, &settlements, prices.as_ref(), price_interpolation, &time_zone, as_of);
+            let years = form_6781_by_year(&contracts);
+            save_section_1256_to_file(&contracts, &years, &(output_file.to_owned() + "_section_1256.csv"), &(output_file.to_owned() + "_form_6781.csv"))
+                .expect("save Section 1256 files");
+            for year in years {
+                println!(
+                    "{}: Section 1256 gain {:.2} (short term {:.2}, long term {:.2})",
+                    year.year, year.total_gain, year.short_term_gain, year.long_term_gain
+                );
+            }
+        }
+
+        let time_zone = read_utc_offset(&cli_args);
+        let filing_status = cli_args.value_of("filing-status").unwrap_or(FILING_STATUS_SINGLE);
+        let opening_carryforward = read_opening_carryforward(&cli_args, tax_events.iter().map(|e| tax_year_of(e.sell_date, &time_zone)).min());
+        let schedule_d = schedule_d_by_year(&tax_events, opening_carryforward.as_ref(), filing_status, &time_zone);
+        save_schedule_d_to_file(&schedule_d, &(output_file.to_owned() + "_schedule_d.csv")).expect("save schedule D file");
+        if let Some(last_year) = schedule_d.last() {
+            save_capital_loss_carryforward_to_file(&last_year.carryforward(), &(output_file.to_owned() + "_loss_carryforward.csv")).expect("save loss carryforward file");
+        }
+
+        if let Some(profile) = read_tax_profile(&cli_args) {
+            let estimates = estimate_tax_by_year(&tax_events, &income, opening_carryforward.as_ref(), &profile, &time_zone);
+            save_tax_estimates_to_file(&estimates, &(output_file.to_owned() + "_tax_estimate.csv")).expect("save tax estimate file");
+            for estimate in estimates {
+                println!(
+                    "{}: estimated tax {:.2} (ordinary {:.2}, long term {:.2}, NIIT {:.2}, state {:.2})",
+                    estimate.year, estimate.total_tax, estimate.ordinary_tax, estimate.long_term_tax, estimate.niit, estimate.state_tax
+                );
+            }
+        }
+
+        if cli_args.is_present("lineage") {
+            save_lineage_to_file(&tax_events, &accounts, &(output_file.to_owned() + "_lineage.csv")).expect("save lineage file");
+        }
+
         if output_transactions_and_tax_events > 0 {
-            save_transactions_and_tax_events_to_file(&transactions, &tax_events, &accounts, "transactions_and_tax_events.csv").expect("save transactions and tax events file");
+            save_transactions_and_tax_events_to_file(&transactions, &tax_events, &accounts, "transactions_and_tax_events.csv", cli_args.is_present("aggregate-tax-events")).expect("save transactions and tax events file");
         }
 
         if output_accounts > 0 {
-            save_accounts_to_file(accounts).expect("save accounts file");
+            save_accounts_to_file(&accounts, "accounts.csv").expect("save accounts file");
         }
     }
 }
 
+fn process_stream(cli_args: &ArgMatches, input_file: &str, output_file: &str, options: &ProcessingOptions, prices: Option<&PriceDatabase>, price_interpolation: &str, save_accounts: bool) -> Result<(), Box<dyn Error>> {
+    let chunk_size = match cli_args.value_of("chunk-size") {
+        Some(chunk_size) => chunk_size.parse::<usize>()?,
+        None => DEFAULT_CHUNK_SIZE,
+    };
+    let transactions: Box<dyn Iterator<Item = TransactionResult>> = if cli_args.is_present("presorted") {
+        Box::new(read_sorted_transactions(input_file)?)
+    } else {
+        Box::new(read_transactions_with_external_sort(input_file, chunk_size)?)
+    };
+    let transactions = transactions.map(|transaction| {
+        let mut transaction = transaction?;
+        if let Some(prices) = prices {
+            for id in fill_missing_usd_values(std::slice::from_mut(&mut transaction), prices, price_interpolation) {
+                eprintln!("transaction {}: no usd_value and no price available", id);
+            }
+            for warning in validate_usd_values(std::slice::from_ref(&transaction), prices, price_interpolation, USD_VALUE_TOLERANCE) {
+                eprintln!("{}", warning);
+            }
+        }
+        Ok(transaction)
+    });
+
+    let mut accounts = default_accounts();
+    if let Some(opening_accounts_file) = cli_args.value_of("opening-accounts") {
+        accounts.extend(read_accounts_from_file(opening_accounts_file)?);
+    }
+
+    let long_gains_file = output_file.to_owned() + "_long_gains.csv";
+    let short_gains_file = output_file.to_owned() + "_short_gains.csv";
+    let income_file = output_file.to_owned() + "_income.csv";
+    let output = StreamOutput {
+        long_gains_file: Path::new(&long_gains_file),
+        short_gains_file: Path::new(&short_gains_file),
+        income_file: Path::new(&income_file),
+        period: read_tax_period(cli_args),
+    };
+    let (accounts, summary) = process_transactions_stream(transactions, accounts, options, &output, chunk_size)?;
+    println!("Processed {} transactions into {} tax events and {} income events.", summary.transactions, summary.tax_events, summary.income_events);
+
+    if save_accounts {
+        save_accounts_to_file(&accounts, "accounts.csv")?;
+    }
+    Ok(())
+}
+
+/// The prices of the --price-snapshot file, or else the prices loaded with -p at `datetime`.
+fn read_price_snapshot_at(cli_args: &ArgMatches, prices: Option<&PriceDatabase>, accounts: &HashMap<String, Account>, datetime: DateTime<Utc>, price_interpolation: &str) -> HashMap<String, f64> {
+    match cli_args.value_of("price-snapshot") {
+        Some(snapshot_file) => read_price_snapshot(snapshot_file).expect("read price snapshot"),
+        None => prices
+            .expect("a price snapshot or prices")
+            .snapshot(accounts.keys(), datetime, price_interpolation),
+    }
+}
+
+/// The tax brackets when --tax-brackets is given, otherwise the flat rates.
+fn read_tax_estimator(cli_args: &ArgMatches) -> Box<dyn TaxEstimator> {
+    if let Some(profile) = read_tax_profile(cli_args) {
+        return Box::new(profile);
+    }
+    Box::new(read_tax_rates(cli_args))
+}
+
+fn read_tax_rates(cli_args: &ArgMatches) -> TaxRates {
+    let parse_rate = |name, default| cli_args.value_of(name).map(|rate: &str| rate.parse::<f64>().expect("a tax rate such as 0.24")).unwrap_or(default);
+    TaxRates {
+        short_term: parse_rate("short-term-rate", DEFAULT_SHORT_TERM_RATE),
+        long_term: parse_rate("long-term-rate", DEFAULT_LONG_TERM_RATE),
+    }
+}
+
+/// The capital losses carried into `first_year` from the --loss-carryforward file.
+fn read_opening_carryforward(cli_args: &ArgMatches, first_year: Option<i32>) -> Option<CapitalLossCarryforward> {
+    let carryforward_file = cli_args.value_of("loss-carryforward")?;
+    read_capital_loss_carryforward(carryforward_file, first_year?).expect("read capital loss carryforward")
+}
+
+fn read_tax_profile(cli_args: &ArgMatches) -> Option<TaxProfile> {
+    let brackets_file = cli_args.value_of("tax-brackets")?;
+    let brackets = read_tax_brackets(brackets_file).expect("read tax brackets");
+    let filing_status = cli_args.value_of("filing-status").unwrap_or(FILING_STATUS_SINGLE);
+    if !brackets.has_filing_status(filing_status) {
+        panic!("No {} tax brackets in {}", filing_status, brackets_file);
+    }
+    Some(TaxProfile {
+        brackets,
+        filing_status: filing_status.to_string(),
+        other_income: cli_args
+            .value_of("other-income")
+            .map(|amount| amount.parse::<f64>().expect("other income amount"))
+            .unwrap_or(0.0),
+        deductions: cli_args
+            .value_of("deductions")
+            .map(|amount| amount.parse::<f64>().expect("deductions amount"))
+            .unwrap_or(0.0),
+    })
+}
+
+fn read_utc_offset(cli_args: &ArgMatches) -> FixedOffset {
+    parse_utc_offset(cli_args.value_of("utc-offset").unwrap_or("UTC")).expect("UTC offset")
+}
+
+fn read_tax_period(cli_args: &ArgMatches) -> Option<TaxPeriod> {
+    let time_zone = read_utc_offset(cli_args);
+    let parse_date = |name| {
+        cli_args
+            .value_of(name)
+            .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").expect("date in YYYY-MM-DD format"))
+    };
+
+    if let Some(year) = cli_args.value_of("tax-year") {
+        Some(TaxPeriod::tax_year(year.parse().expect("tax year"), &time_zone))
+    } else if cli_args.is_present("from") || cli_args.is_present("to") {
+        Some(TaxPeriod::date_range(parse_date("from"), parse_date("to"), &time_zone))
+    } else {
+        None
+    }
+}
+
 pub fn read_transactions(file_path: &str) -> Result<Vec<Transaction>, Box<Error>> {
     let file = File::open(file_path)?;
     let mut reader = csv::Reader::from_reader(file);
@@ -133,7 +824,75 @@ pub fn read_transactions(file_path: &str) -> Result<Vec<Transaction>, Box<Error>
     Ok(transactions)
 }
 
-fn save_to_file(tax_events: &Vec<TaxEvent>, out_file: &str, filter_by: &str) {
+#[derive(Debug, Deserialize)]
+struct AccountRecord {
+    #[serde(rename = "Account")]
+    account: String,
+    #[serde(rename = "Deposit datetime")]
+    deposit_datetime: String,
+    #[serde(rename = "Deposit USD value")]
+    deposit_usd_value: f64,
+    #[serde(rename = "Deposit quantity")]
+    deposit_quantity: f64,
+    #[serde(rename = "Deposit remaining quantity")]
+    deposit_remaining_quantity: f64,
+    #[serde(rename = "Deposit transaction id", default)]
+    deposit_transaction_id: Option<String>,
+    #[serde(rename = "Deposit parents", default)]
+    deposit_parents: Option<String>,
+    #[serde(rename = "Deposit wallet", default)]
+    deposit_wallet: Option<String>,
+    #[serde(rename = "Deposit parent asset", default)]
+    deposit_parent_asset: Option<String>,
+}
+
+/// Reads an accounts file written by `save_accounts_to_file`. Only lots with a remaining quantity are kept.
+pub fn read_accounts_from_file(file_path: &str) -> Result<HashMap<String, Account>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut accounts: HashMap<String, Account> = HashMap::new();
+    for (row, record) in reader.deserialize().enumerate() {
+        let record: AccountRecord = record?;
+        let account = accounts
+            .entry(record.account.clone())
+            .or_insert_with(|| Account::new(record.account.clone(), 0.0));
+        if record.deposit_remaining_quantity <= 0.0 {
+            continue;
+        }
+        let opening_transaction_id = format!("opening-{}-{}", record.account, row + 1);
+        let wallet = record.deposit_wallet.filter(|wallet| !wallet.is_empty());
+        // Lots saved from a run that tracked them per wallet stay allocated to their wallets.
+        account.wallets_allocated |= wallet.is_some();
+        account.add_lot(Deposit {
+            transaction_id: record
+                .deposit_transaction_id
+                .filter(|id| !id.is_empty())
+                .unwrap_or(opening_transaction_id),
+            parents: record
+                .deposit_parents
+                .map(|parents| parents.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+            parent_asset: record.deposit_parent_asset.filter(|asset| !asset.is_empty()),
+            datetime: record.deposit_datetime.parse()?,
+            quantity: record.deposit_quantity,
+            usd_value: record.deposit_usd_value,
+            remaining_quantity: record.deposit_remaining_quantity,
+            wallet,
+        });
+    }
+
+    Ok(accounts)
+}
+
+pub fn read_prices(file_paths: Vec<&str>) -> Result<PriceDatabase, Box<dyn Error>> {
+    let mut prices = PriceDatabase::new();
+    for file_path in file_paths {
+        prices.load_file(file_path)?;
+    }
+    Ok(prices)
+}
+
+fn save_to_file(tax_events: &[TaxEvent], out_file: &str, filter_by: &str) {
     let file = File::create(out_file)
         .ok()
         .expect("Unable to create output file.");
@@ -141,13 +900,7 @@ fn save_to_file(tax_events: &Vec<TaxEvent>, out_file: &str, filter_by: &str) {
     let mut writer = csv::Writer::from_writer(file);
 
     for tax_event in tax_events.iter() {
-        let time_elapsed = tax_event
-            .sell_date
-            .signed_duration_since(tax_event.buy_date);
-
-        if (time_elapsed >= Duration::days(365) && filter_by == CAPITAL_GAIN_TYPE_LONG)
-            || (time_elapsed < Duration::days(365) && filter_by == CAPITAL_GAIN_TYPE_SHORT)
-        {
+        if tax_event.term == filter_by {
             writer
                 .serialize(tax_event)
                 .ok()
@@ -156,19 +909,29 @@ fn save_to_file(tax_events: &Vec<TaxEvent>, out_file: &str, filter_by: &str) {
     }
 }
 
-fn save_accounts_to_file(accounts: HashMap<String,Account>) -> Result<(), Box<dyn Error>>{
-    let out_file = "accounts.csv";
+fn save_income_to_file(income: &[IncomeEvent], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let file = File::create(out_file)?;
+    let mut writer = csv::Writer::from_writer(file);
+    for income_event in income {
+        writer.serialize(income_event)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn save_accounts_to_file(accounts: &HashMap<String,Account>, out_file: &str) -> Result<(), Box<dyn Error>>{
     let file = File::create(out_file)
         .ok()
         .expect("Unable to create output file.");
 
     let mut wtr = csv::Writer::from_writer(file);
 
-    wtr.write_record(&["Account", "Balance", "Deposit datetime", "Deposit USD value", "Deposit quantity", "Deposit remaining quantity"])?;
-    for (_currency, mut acct) in accounts {
-        acct.deposits.sort_by(|a,b| a.datetime.cmp(&b.datetime));
-        for dep in acct.deposits {
-            wtr.write_record(vec![acct.name.clone(), acct.balance.to_string(), dep.datetime.to_string(), dep.usd_value.to_string(), dep.quantity.to_string(), dep.remaining_quantity.to_string()])?;
+    wtr.write_record(["Account", "Balance", "Deposit datetime", "Deposit USD value", "Deposit quantity", "Deposit remaining quantity", "Deposit transaction id", "Deposit parents", "Deposit wallet", "Deposit parent asset"])?;
+    for acct in accounts.values() {
+        let mut deposits = acct.deposits.iter().collect::<Vec<&Deposit>>();
+        deposits.sort_by_key(|d| d.datetime);
+        for dep in deposits {
+            wtr.write_record(vec![acct.name.clone(), acct.balance.to_string(), dep.datetime.to_string(), dep.usd_value.to_string(), dep.quantity.to_string(), dep.remaining_quantity.to_string(), dep.transaction_id.clone(), dep.parents.join(" "), dep.wallet.clone().unwrap_or_default(), dep.parent_asset.clone().unwrap_or_default()])?;
         }
     }
 
@@ -176,39 +939,111 @@ fn save_accounts_to_file(accounts: HashMap<String,Account>) -> Result<(), Box<dy
     Ok(())
 }
 
-fn save_transactions_and_tax_events_to_file(transactions: &Vec<Transaction>, tax_events: &Vec<TaxEvent>, accounts: &HashMap<String, Account>, out_file: &str) -> Result<(), Box<dyn Error>>{
+/// Writes the long gains, short gains, income and year-end accounts of every year to `<output_dir>/<year>/`.
+fn save_reports_by_year(output_dir: &str, tax_events: &[TaxEvent], income: &[IncomeEvent], year_end_accounts: &BTreeMap<i32, HashMap<String, Account>>, time_zone: &FixedOffset) -> Result<(), Box<dyn Error>> {
+    for (year, accounts) in year_end_accounts {
+        let year_dir = Path::new(output_dir).join(year.to_string());
+        fs::create_dir_all(&year_dir)?;
+        let year_file = |name: &str| year_dir.join(name).to_string_lossy().into_owned();
+
+        let year_tax_events = tax_events
+            .iter()
+            .filter(|e| tax_year_of(e.sell_date, time_zone) == *year)
+            .cloned()
+            .collect::<Vec<TaxEvent>>();
+        save_to_file(&year_tax_events, &year_file("long_gains.csv"), CAPITAL_GAIN_TYPE_LONG);
+        save_to_file(&year_tax_events, &year_file("short_gains.csv"), CAPITAL_GAIN_TYPE_SHORT);
+
+        let year_income = income
+            .iter()
+            .filter(|i| tax_year_of(i.datetime, time_zone) == *year)
+            .cloned()
+            .collect::<Vec<IncomeEvent>>();
+        if !year_income.is_empty() {
+            save_income_to_file(&year_income, &year_file("income.csv"))?;
+        }
+
+        save_accounts_to_file(accounts, &year_file("accounts.csv"))?;
+    }
+    Ok(())
+}
+
+/// Writes every transaction with its tax events, joined by transaction id. With `aggregate` each transaction is a
+/// single row with the totals of its tax events, otherwise there is a row for every lot the transaction consumed.
+/// The remaining quantity is that of the lot created by the transaction, empty when it didn't create one.
+fn save_transactions_and_tax_events_to_file(transactions: &[Transaction], tax_events: &[TaxEvent], accounts: &HashMap<String, Account>, out_file: &str, aggregate: bool) -> Result<(), Box<dyn Error>>{
     let file = File::create(out_file)
         .ok()
         .expect("the output file to be created.");
 
     let mut wtr = csv::Writer::from_writer(file);
 
-    wtr.write_record(&["id","datetime","origin_wallet","origin_asset","origin_quantity","destination_wallet","destination_asset","destination_quantity","remaining_quantity","usd_value","usd_fee","buy_date","cost_basis","gain"])?;
-    for transaction in transactions {
-        let acct = accounts.get(transaction.destination_asset.as_str()).unwrap();
-        let mut remaining_quantity= transaction.destination_quantity;
-        for dep in &acct.deposits {
-            if dep.datetime == transaction.datetime{
-                remaining_quantity = dep.remaining_quantity;
-            }
+    let mut tax_events_by_transaction: HashMap<&str, Vec<&TaxEvent>> = HashMap::new();
+    for tax_event in tax_events {
+        tax_events_by_transaction.entry(tax_event.sell_transaction_id.as_str()).or_default().push(tax_event);
+    }
+    // Lots split between wallets share their transaction id, lots carried over to another asset keep it.
+    let mut remaining_quantities: HashMap<(&str, &str), f64> = HashMap::new();
+    for (asset, acct) in accounts.iter() {
+        for deposit in acct.deposits.iter() {
+            *remaining_quantities.entry((asset.as_str(), deposit.transaction_id.as_str())).or_default() += deposit.remaining_quantity;
         }
+    }
+
+    wtr.write_record(["id","datetime","origin_wallet","origin_asset","origin_quantity","destination_wallet","destination_asset","destination_quantity","remaining_quantity","usd_value","usd_fee","buy_date","buy_transaction_id","term","quantity","cost_basis","proceeds","gain","fee","method"])?;
+    for transaction in transactions {
+        let remaining_quantity = remaining_quantities
+            .get(&(transaction.destination_asset.as_str(), transaction.id.as_str()))
+            .map(|q| q.to_string())
+            .unwrap_or_default();
+        let transaction_columns = vec![transaction.id.clone(), transaction.datetime.to_string(), transaction.origin_wallet.clone(),transaction.origin_asset.clone(),
+                                       transaction.origin_quantity.to_string(),
+                                       transaction.destination_wallet.clone(), transaction.destination_asset.clone(), transaction.destination_quantity.to_string(),
+                                       remaining_quantity,
+                                       transaction.usd_value.map(|v| v.to_string()).unwrap_or_default(),
+                                       transaction.usd_fee.unwrap_or_default().to_string()];
 
-        let mut buy_date = "".to_string();
-        let mut cost_basis = 0.0;
-        let mut gain = 0.0;
-        for tax_event in tax_events {
-            if tax_event.sell_date == transaction.datetime {
-                buy_date = tax_event.buy_date.to_string();
-                cost_basis = tax_event.cost_basis;
-                gain = tax_event.gain;
+        let transaction_tax_events = tax_events_by_transaction
+            .get(transaction.id.as_str())
+            .cloned()
+            .unwrap_or_default();
+        if transaction_tax_events.is_empty() {
+            let mut record = transaction_columns;
+            record.extend(vec![String::new(); 9]);
+            wtr.write_record(record)?;
+        } else if aggregate {
+            let mut terms = transaction_tax_events.iter().map(|e| e.term.as_str()).collect::<Vec<&str>>();
+            terms.dedup();
+            let mut record = transaction_columns;
+            record.extend(vec![
+                transaction_tax_events.iter().map(|e| e.buy_date).min().unwrap().to_string(),
+                transaction_tax_events.iter().map(|e| e.buy_transaction_id.as_str()).collect::<Vec<&str>>().join(" "),
+                if terms.len() == 1 { terms[0].to_string() } else { "mixed".to_string() },
+                transaction_tax_events.iter().map(|e| e.quantity).sum::<f64>().to_string(),
+                round_to_dollars(transaction_tax_events.iter().map(|e| e.cost_basis).sum()).to_string(),
+                round_to_dollars(transaction_tax_events.iter().map(|e| e.proceeds).sum()).to_string(),
+                round_to_dollars(transaction_tax_events.iter().map(|e| e.gain).sum()).to_string(),
+                round_to_dollars(transaction_tax_events.iter().map(|e| e.fee).sum()).to_string(),
+                transaction_tax_events[0].method.clone(),
+            ]);
+            wtr.write_record(record)?;
+        } else {
+            for tax_event in transaction_tax_events {
+                let mut record = transaction_columns.clone();
+                record.extend(vec![
+                    tax_event.buy_date.to_string(),
+                    tax_event.buy_transaction_id.clone(),
+                    tax_event.term.clone(),
+                    tax_event.quantity.to_string(),
+                    tax_event.cost_basis.to_string(),
+                    tax_event.proceeds.to_string(),
+                    tax_event.gain.to_string(),
+                    tax_event.fee.to_string(),
+                    tax_event.method.clone(),
+                ]);
+                wtr.write_record(record)?;
             }
         }
-        wtr.write_record(vec![transaction.id.clone(), transaction.datetime.to_string(), transaction.origin_wallet.clone(),transaction.origin_asset.clone(),
-                              transaction.origin_quantity.to_string(),
-                              transaction.destination_wallet.clone(), transaction.destination_asset.clone(), transaction.destination_quantity.to_string(),
-                              remaining_quantity.to_string(),
-                              transaction.usd_value.to_string(),
-                              transaction.usd_fee.unwrap_or_default().to_string(),buy_date, cost_basis.to_string(), gain.to_string()])?;
     }
 
     wtr.flush()?;
@@ -217,8 +1052,8 @@ fn save_transactions_and_tax_events_to_file(transactions: &Vec<Transaction>, tax
 
 #[cfg(test)]
 mod tests {
-    use chrono::{DateTime, TimeZone, Utc};
-    use account::{TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_HIFO};
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use account::{TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_HIFO, TAX_ACCOUNTING_METHOD_SPECIFIC_ID};
     use super::*;
 
     lazy_static!(
@@ -237,8 +1072,11 @@ mod tests {
             destination_wallet: WALLET_KRAKEN.to_string(),
             destination_asset: "ETH".to_string(),
             destination_quantity: 1.0,
-            usd_value: 2250.0,
-            usd_fee: None
+            usd_value: Some(2250.0),
+            usd_fee: None,
+            fee_asset: None,
+            sequence: None,
+            type_: None,
         };
         let t1 = Transaction{
             id: "1".to_string(),
@@ -249,8 +1087,11 @@ mod tests {
             destination_wallet: WALLET_KRAKEN.to_string(),
             destination_asset: "ETH".to_string(),
             destination_quantity: 1.0,
-            usd_value: 2500.0,
-            usd_fee: None
+            usd_value: Some(2500.0),
+            usd_fee: None,
+            fee_asset: None,
+            sequence: None,
+            type_: None,
         };
         let t2 = Transaction{
             id: "2".to_string(),
@@ -261,8 +1102,11 @@ mod tests {
             destination_wallet: WALLET_BANK.to_string(),
             destination_asset: "USD".to_string(),
             destination_quantity: 3000.0,
-            usd_value: 3000.0,
-            usd_fee: None
+            usd_value: Some(3000.0),
+            usd_fee: None,
+            fee_asset: None,
+            sequence: None,
+            type_: None,
         };
 
         vec![t0,t1,t2]
@@ -286,11 +1130,1024 @@ mod tests {
         assert_eq!(tax_events.get(0).unwrap().gain, 500.0);
     }
 
+    #[test]
+    fn price_interpolation() {
+        let mut prices = PriceDatabase::new();
+        prices.insert("BTC", *DATE_TIME0, 1000.0);
+        prices.insert("BTC", *DATE_TIME0 + Duration::days(2), 2000.0);
+        let between = *DATE_TIME0 + Duration::hours(36);
+
+        assert_eq!(prices.price_at("BTC", between, PRICE_INTERPOLATION_PREVIOUS), Some(1000.0));
+        assert_eq!(prices.price_at("BTC", between, PRICE_INTERPOLATION_NEAREST), Some(2000.0));
+        assert_eq!(prices.price_at("XBT", between, PRICE_INTERPOLATION_LINEAR), Some(1750.0));
+        assert_eq!(prices.price_at("BTC", *DATE_TIME2, PRICE_INTERPOLATION_PREVIOUS), None);
+    }
+
+    #[test]
+    fn fill_missing_usd_values_from_prices() {
+        let mut prices = PriceDatabase::new();
+        prices.insert("ETH", *DATE_TIME2, 2800.0);
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].origin_asset = "ETH".to_string();
+        transactions[2].destination_asset = "BTC".to_string();
+        transactions[2].usd_value = None;
+        // A gift recorded at 0 is a value, not a missing one.
+        transactions[0].usd_value = Some(0.0);
+
+        let unpriced = fill_missing_usd_values(&mut transactions, &prices, PRICE_INTERPOLATION_PREVIOUS);
+        assert!(unpriced.is_empty());
+        assert_eq!(transactions[2].usd_value, Some(2800.0));
+        assert_eq!(transactions[0].usd_value, Some(0.0));
+        let warnings = validate_usd_values(&transactions, &prices, PRICE_INTERPOLATION_PREVIOUS, USD_VALUE_TOLERANCE);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("transaction 0:"));
+    }
+
+    #[test]
+    fn opening_accounts_from_previous_year() {
+        let mut previous_year = test_transactions_eth_buy2_sell1();
+        previous_year.truncate(2);
+        let (_, accounts) = calculate_capital_gains(&mut previous_year, TAX_ACCOUNTING_METHOD_FIFO);
+        let accounts_file = std::env::temp_dir().join("ptt_opening_accounts_test.csv");
+        save_accounts_to_file(&accounts, accounts_file.to_str().unwrap()).expect("the file to be saved");
+
+        let mut accounts = default_accounts();
+        accounts.extend(read_accounts_from_file(accounts_file.to_str().unwrap()).expect("the file to be read"));
+        assert_eq!(accounts["ETH"].balance, 2.0);
+
+        let mut this_year = test_transactions_eth_buy2_sell1();
+        this_year.drain(0..2);
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut this_year, accounts, &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO));
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!(tax_events[0].buy_date, *DATE_TIME0);
+        assert_eq!(tax_events[0].gain, 750.0);
+        assert_eq!(accounts["ETH"].balance, 1.0);
+    }
+
+    #[test]
+    fn tax_year_in_time_zone() {
+        let new_york = parse_utc_offset("-05:00").unwrap();
+        let period = TaxPeriod::tax_year(2016, &new_york);
+        let new_years_eve_in_new_york = Utc.with_ymd_and_hms(2017, 1, 1, 3, 0, 0).unwrap();
+
+        assert!(period.contains(new_years_eve_in_new_york));
+        assert!(!TaxPeriod::tax_year(2016, &parse_utc_offset("UTC").unwrap()).contains(new_years_eve_in_new_york));
+
+        let (tax_events, _) = calculate_capital_gains(&mut test_transactions_eth_buy2_sell1(), TAX_ACCOUNTING_METHOD_FIFO);
+        let march = TaxPeriod::date_range(NaiveDate::from_ymd_opt(2017, 3, 1), None, &new_york);
+        assert_eq!(tax_events.iter().filter(|e| march.contains(e.sell_date)).count(), 0);
+    }
+
+    #[test]
+    fn income_adds_lots_without_selling() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[1].origin_wallet = WALLET_INCOME.to_string();
+        transactions[1].origin_asset = "ETH".to_string();
+        transactions[1].origin_quantity = 1.0;
+
+        let (tax_events, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_LIFO);
+        let income = calculate_income(&transactions);
+        assert_eq!(income.len(), 1);
+        assert_eq!(income[0].usd_value, 2500.0);
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!(tax_events[0].gain, 500.0);
+        assert_eq!(accounts["ETH"].balance, 1.0);
+    }
+
+    #[test]
+    fn year_end_accounts_from_one_replay() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[0].datetime = Utc.with_ymd_and_hms(2015, 6, 1, 0, 0, 0).unwrap();
+        let utc = parse_utc_offset("UTC").unwrap();
+
+        let (tax_events, year_end_accounts) = calculate_capital_gains_by_year(&mut transactions, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO), &utc);
+        assert_eq!(year_end_accounts.keys().cloned().collect::<Vec<i32>>(), vec![2015, 2016, 2017]);
+        assert_eq!(year_end_accounts[&2015]["ETH"].balance, 1.0);
+        assert_eq!(year_end_accounts[&2016]["ETH"].balance, 1.0);
+        assert_eq!(year_end_accounts[&2017]["ETH"].balance, 1.0);
+        assert_eq!(tax_events.len(), 1);
+
+        let output_dir = std::env::temp_dir().join("ptt_by_year_test");
+        save_reports_by_year(output_dir.to_str().unwrap(), &tax_events, &[], &year_end_accounts, &utc).expect("the reports to be saved");
+        assert!(output_dir.join("2017").join("long_gains.csv").exists());
+        assert!(output_dir.join("2016").join("accounts.csv").exists());
+    }
+
+    #[test]
+    fn unrealized_gains_by_term() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions.truncate(2);
+        let (_, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_FIFO);
+        let prices = hashmap! { "ETH".to_string() => 2000.0 };
+        let one_year_after_first_buy = *DATE_TIME0 + Duration::days(365);
+
+        let (lots, assets) = calculate_unrealized_gains(&accounts, &prices, one_year_after_first_buy);
+        assert_eq!(lots.len(), 2);
+        assert_eq!(lots[0].term, CAPITAL_GAIN_TYPE_LONG);
+        assert_eq!(lots[1].term, CAPITAL_GAIN_TYPE_SHORT);
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].long_term_gain, -250.0);
+        assert_eq!(assets[0].short_term_gain, -500.0);
+        assert_eq!(assets[0].market_value, 4000.0);
+    }
+
+    #[test]
+    fn general_ledger_balances() {
+        let ledger = post_transactions(&mut test_transactions_eth_buy2_sell1(), default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO));
+        assert!(ledger.unbalanced_entries().is_empty());
+        assert!(ledger.is_balanced());
+
+        let trial_balance = ledger.trial_balance();
+        let gains = trial_balance.iter().find(|row| row.account == LEDGER_ACCOUNT_REALIZED_GAINS).unwrap();
+        assert_eq!(gains.balance, -750.0);
+        let eth = trial_balance.iter().find(|row| row.account == "Assets:Crypto:ETH").unwrap();
+        assert_eq!(eth.balance, 2500.0);
+
+        // A fee paid in ETH is taken from ETH, not from the fiat account.
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].usd_fee = Some(10.0);
+        transactions[2].fee_asset = Some("ETH".to_string());
+        let ledger = post_transactions(&mut transactions, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO));
+        assert!(ledger.is_balanced());
+        let trial_balance = ledger.trial_balance();
+        let balance = |account: &str| trial_balance.iter().find(|row| row.account == account).unwrap().balance;
+        assert_eq!(balance("Assets:Crypto:ETH"), 2490.0);
+        assert_eq!(balance("Assets:Fiat:USD"), -1750.0);
+        assert_eq!(balance(LEDGER_ACCOUNT_FEES), 10.0);
+    }
+
+    #[test]
+    fn general_ledger_reports_unbalanced_entries() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].origin_quantity = 3.0;
+
+        let ledger = post_transactions(&mut transactions, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO));
+        let unbalanced_entries = ledger.unbalanced_entries();
+        assert_eq!(unbalanced_entries.len(), 1);
+        assert_eq!(unbalanced_entries[0].transaction_id, "2");
+        assert!(!ledger.is_balanced());
+    }
+
+    #[test]
+    fn reconcile_wallet_balances() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[1].destination_quantity = 1.5;
+        let statement = |datetime: DateTime<Utc>, quantity: f64| BalanceStatement {
+            wallet: WALLET_KRAKEN.to_string(),
+            asset: "ETH".to_string(),
+            datetime,
+            quantity,
+        };
+        let statements = vec![statement(*DATE_TIME0, 1.0), statement(*DATE_TIME2, 1.0)];
+
+        let rows = reconcile(&transactions, &default_accounts(), &statements);
+        assert!(rows[0].is_match());
+        assert!(!rows[1].is_match());
+        assert_eq!(rows[1].difference, 0.5);
+        assert_eq!(rows[1].transactions_to_review, "1 2");
+
+        // The wallets start with the opening lots.
+        let mut opening_accounts = default_accounts();
+        let mut lot = Deposit::new("opening", *DATE_TIME0 - Duration::days(1), 0.5, 500.0);
+        lot.wallet = Some(WALLET_KRAKEN.to_string());
+        opening_accounts.get_mut("ETH").unwrap().add_lot(lot);
+        let rows = reconcile(&transactions, &opening_accounts, &[statement(*DATE_TIME0, 1.5), statement(*DATE_TIME2, 1.5)]);
+        assert!(rows[0].is_match());
+        assert_eq!(rows[1].difference, 0.5);
+    }
+
+    #[test]
+    fn same_time_ordering_and_deposits() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].datetime = *DATE_TIME1;
+        transactions[2].origin_quantity = 2.0;
+        transactions.reverse();
+
+        assert_eq!(same_timestamp_conflicts(&transactions).len(), 1);
+
+        let (tax_events, _) = calculate_capital_gains(&mut transactions.clone(), TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!(tax_events.len(), 1);
+
+        let mut options = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO);
+        options.same_time_deposits_spendable = true;
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions, default_accounts(), &options);
+        assert_eq!(transactions.iter().map(|t| t.id.as_str()).collect::<Vec<&str>>(), vec!["0", "1", "2"]);
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!(accounts["ETH"].balance, 0.0);
+
+        transactions[2].sequence = Some(0);
+        transactions[1].sequence = Some(1);
+        sort_transactions(&mut transactions);
+        assert_eq!(transactions.iter().map(|t| t.id.as_str()).collect::<Vec<&str>>(), vec!["0", "2", "1"]);
+    }
+
+    #[test]
+    fn lineage_through_swaps() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[1].origin_wallet = WALLET_KRAKEN.to_string();
+        transactions[1].origin_asset = "ETH".to_string();
+        transactions[1].origin_quantity = 1.0;
+        transactions[1].destination_asset = "BTC".to_string();
+        transactions[2].origin_asset = "BTC".to_string();
+
+        let (tax_events, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!(tax_events[1].buy_transaction_id, "1");
+
+        let lineage = LotIndex::new(&accounts).lineage(1, &tax_events[1]);
+        let chain = lineage.iter().map(|step| (step.depth, step.transaction_id.as_str(), step.asset.as_str())).collect::<Vec<_>>();
+        assert_eq!(chain, vec![(0, "1", "BTC"), (1, "0", "ETH")]);
+        assert_eq!(lineage[1].spent_for, "1");
+    }
+
+    #[test]
+    fn tax_events_trace_back_to_transactions() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].usd_fee = Some(10.0);
+        transactions[2].origin_quantity = 2.0;
+        let (tax_events, _) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_HIFO);
+
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!(tax_events[0].sell_transaction_id, "2");
+        assert_eq!(tax_events[0].buy_transaction_id, "1");
+        assert_eq!(tax_events[1].buy_transaction_id, "0");
+        assert_eq!(tax_events[0].origin_wallet, WALLET_KRAKEN);
+        assert_eq!(tax_events[0].received_asset, "USD");
+        assert_eq!(tax_events[0].method, TAX_ACCOUNTING_METHOD_HIFO);
+        assert_eq!(tax_events[0].term, CAPITAL_GAIN_TYPE_SHORT);
+        assert_eq!(tax_events[0].fee, 5.0);
+    }
+
+    #[test]
+    fn hifo_uses_highest_unit_cost() {
+        // Lot 0 costs 4500 per ETH for a cost basis of 2250, lot 1 costs 2500 per ETH for a cost basis of 2500.
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[0].destination_quantity = 0.5;
+        let (tax_events, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_HIFO);
+
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!(tax_events[0].buy_transaction_id, "0");
+        assert_eq!(tax_events[0].gain, -750.0);
+        assert_eq!(tax_events[1].buy_transaction_id, "1");
+        assert_eq!(accounts["ETH"].balance, 0.5);
+    }
+
+    /// A trading bot buying and selling BTC every minute. Deterministic, so runs can be compared.
+    fn benchmark_transactions(count: usize) -> Vec<Transaction> {
+        let mut seed: u64 = 42;
+        let mut price = 10000.0;
+        (0..count)
+            .map(|i| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                price = (price * (0.99 + (seed >> 33) as f64 / (1u64 << 31) as f64 * 0.02)).max(100.0);
+                let quantity = 0.01 + ((seed >> 40) % 100) as f64 / 1000.0;
+                let buy = i % 3 != 2;
+                let (origin_asset, origin_quantity, destination_asset, destination_quantity) = if buy {
+                    ("USD", quantity * price, "BTC", quantity)
+                } else {
+                    ("BTC", quantity, "USD", quantity * price)
+                };
+                Transaction {
+                    id: i.to_string(),
+                    datetime: *DATE_TIME0 + Duration::minutes(i as i64),
+                    origin_wallet: WALLET_KRAKEN.to_string(),
+                    origin_asset: origin_asset.to_string(),
+                    origin_quantity,
+                    destination_wallet: WALLET_KRAKEN.to_string(),
+                    destination_asset: destination_asset.to_string(),
+                    destination_quantity,
+                    usd_value: Some(quantity * price),
+                    usd_fee: None,
+                    fee_asset: None,
+                    sequence: None,
+                    type_: None,
+                }
+            })
+            .collect()
+    }
+
+    /// cargo test --release -- --ignored --nocapture benchmark
+    #[test]
+    #[ignore]
+    fn benchmark_million_trades() {
+        for method in [TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_LIFO, TAX_ACCOUNTING_METHOD_HIFO].iter() {
+            let mut transactions = benchmark_transactions(1_000_000);
+            let started = std::time::Instant::now();
+            let (tax_events, _) = calculate_capital_gains(&mut transactions, method);
+            println!("{}: {} tax events in {:?}", method, tax_events.len(), started.elapsed());
+        }
+    }
+
+    #[test]
+    fn compare_methods_by_year() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        let mut t3 = transactions[2].clone();
+        t3.id = "3".to_string();
+        t3.datetime = Utc.with_ymd_and_hms(2018, 3, 1, 0, 1, 1).unwrap();
+        transactions.push(t3);
+        let rates = TaxRates {
+            short_term: 0.2,
+            long_term: 0.1,
+        };
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let rows = compare_accounting_methods(&transactions, &default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO), None, FILING_STATUS_SINGLE, &utc, &rates);
+        let row = |method, year| rows.iter().find(|r| r.method == method && r.year == year).expect("a row for every method and year");
+
+        assert_eq!(rows.len(), 6);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2017).short_term_gain, 750.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2017).estimated_tax, 150.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2017).basis_carried_forward, 2500.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2018).long_term_gain, 500.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2018).estimated_tax, 50.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_LIFO, 2017).short_term_gain, 500.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_LIFO, 2017).basis_carried_forward, 2250.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_LIFO, 2018).long_term_gain, 750.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_LIFO, 2018).basis_carried_forward, 0.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_HIFO, 2018).estimated_tax, 75.0);
+        assert_eq!(rates.estimate(1000.0, -400.0), 120.0);
+
+        // A loss carried over is netted with the gains of each method, and what is left is carried to the next year.
+        let opening = CapitalLossCarryforward {
+            year: 2016,
+            short_term: 0.0,
+            long_term: 5000.0,
+        };
+        let rows = compare_accounting_methods(&transactions, &default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO), Some(&opening), FILING_STATUS_SINGLE, &utc, &rates);
+        let row = |method, year| rows.iter().find(|r| r.method == method && r.year == year).expect("a row for every method and year");
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2017).short_term_gain, 750.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2017).estimated_tax, 0.0);
+        assert_eq!(row(TAX_ACCOUNTING_METHOD_FIFO, 2018).estimated_tax, 0.0);
+    }
+
+    #[test]
+    fn incremental_tax_with_brackets() {
+        let mut brackets = TaxBrackets::default();
+        let bracket = |kind: &str, threshold, rate| TaxBracket {
+            year: 2017,
+            filing_status: FILING_STATUS_SINGLE.to_string(),
+            kind: kind.to_string(),
+            threshold,
+            rate,
+        };
+        brackets.insert(bracket(BRACKET_KIND_ORDINARY, 10000.0, 0.2));
+        brackets.insert(bracket(BRACKET_KIND_ORDINARY, 0.0, 0.1));
+        brackets.insert(bracket(BRACKET_KIND_LONG_TERM, 0.0, 0.0));
+        brackets.insert(bracket(BRACKET_KIND_LONG_TERM, 20000.0, 0.15));
+        brackets.insert(bracket(BRACKET_KIND_NIIT, 25000.0, 0.04));
+        brackets.insert(bracket(BRACKET_KIND_STATE, 0.0, 0.05));
+        let mut profile = TaxProfile {
+            brackets,
+            filing_status: FILING_STATUS_SINGLE.to_string(),
+            other_income: 8000.0,
+            deductions: 0.0,
+        };
+
+        // 2000 short term at 10%, 1000 income at 20%, 9000 long term stacked from 11000 to 20000 at 0%
+        // and 10000 from 20000 to 30000 at 15%. NIIT on the 5000 above 25000, state on the 22000 added.
+        let tax = profile.incremental_tax(2017, 1000.0, 2000.0, 19000.0);
+        assert_eq!(round_to_dollars(tax.ordinary_tax), 400.0);
+        assert_eq!(round_to_dollars(tax.long_term_tax), 1500.0);
+        assert_eq!(round_to_dollars(tax.niit), 200.0);
+        assert_eq!(round_to_dollars(tax.state_tax), 1100.0);
+
+        // A net loss only reduces the income by 3000, at 10% plus 5% of state tax. The 2018 brackets are missing, 2017 is used.
+        assert_eq!(profile.estimate_tax(2018, 0.0, -5000.0, 0.0), -450.0);
+
+        // The NIIT threshold applies to the income before the deductions.
+        profile.deductions = 3000.0;
+        assert_eq!(round_to_dollars(profile.incremental_tax(2017, 1000.0, 2000.0, 19000.0).niit), 320.0);
+    }
+
+    #[test]
+    fn tax_brackets_outside_the_table() {
+        let mut brackets = TaxBrackets::default();
+        for (year, rate) in [(2017, 0.1), (2018, 0.12)] {
+            brackets.insert(TaxBracket {
+                year,
+                filing_status: FILING_STATUS_SINGLE.to_string(),
+                kind: BRACKET_KIND_ORDINARY.to_string(),
+                threshold: 0.0,
+                rate,
+            });
+        }
+        // The closest year of the table is used, with a warning.
+        assert_eq!(brackets.brackets(2016, FILING_STATUS_SINGLE, BRACKET_KIND_ORDINARY), &[(0.0, 0.1)]);
+        assert_eq!(brackets.brackets(2020, FILING_STATUS_SINGLE, BRACKET_KIND_ORDINARY), &[(0.0, 0.12)]);
+        assert!(brackets.brackets(2017, FILING_STATUS_SINGLE, BRACKET_KIND_STATE).is_empty());
+        assert!(!brackets.has_filing_status(FILING_STATUS_HEAD_OF_HOUSEHOLD));
+
+        // The example table has every filing status the command line accepts.
+        let example = read_tax_brackets("tax_brackets.csv").unwrap();
+        for filing_status in [FILING_STATUS_SINGLE, FILING_STATUS_MARRIED_FILING_JOINTLY, FILING_STATUS_MARRIED_FILING_SEPARATELY, FILING_STATUS_HEAD_OF_HOUSEHOLD] {
+            assert!(example.has_filing_status(filing_status));
+        }
+    }
+
+    #[test]
+    fn capital_losses_carried_forward() {
+        let no_carryover = CapitalLossCarryforward::default();
+        let both_losses = net_capital_gains(2017, -2000.0, -5000.0, &no_carryover, FILING_STATUS_SINGLE);
+        assert_eq!(both_losses.loss_deduction, 3000.0);
+        assert_eq!(both_losses.short_term_carryforward, 0.0);
+        assert_eq!(both_losses.long_term_carryforward, 4000.0);
+
+        let short_term_loss = net_capital_gains(2017, -5000.0, 1000.0, &no_carryover, FILING_STATUS_MARRIED_FILING_SEPARATELY);
+        assert_eq!(short_term_loss.loss_deduction, 1500.0);
+        assert_eq!(short_term_loss.short_term_carryforward, 2500.0);
+        assert_eq!(short_term_loss.long_term_carryforward, 0.0);
+
+        // A 10000 long term loss in 2017, nothing in 2018 and a short term gain in 2019.
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].usd_value = Some(0.0);
+        transactions[2].destination_quantity = 0.0;
+        transactions[1].origin_quantity = 10000.0;
+        transactions[1].usd_value = Some(10000.0);
+        transactions[0].datetime = Utc.with_ymd_and_hms(2016, 1, 1, 0, 0, 0).unwrap();
+        transactions[1].datetime = Utc.with_ymd_and_hms(2016, 1, 2, 0, 0, 0).unwrap();
+        let mut t3 = transactions[0].clone();
+        t3.id = "3".to_string();
+        t3.datetime = Utc.with_ymd_and_hms(2019, 1, 1, 0, 0, 0).unwrap();
+        let mut t4 = transactions[2].clone();
+        t4.id = "4".to_string();
+        t4.datetime = Utc.with_ymd_and_hms(2019, 2, 1, 0, 0, 0).unwrap();
+        t4.usd_value = Some(3250.0);
+        transactions.extend(vec![t3, t4]);
+        let (tax_events, _) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_LIFO);
+        let opening = CapitalLossCarryforward {
+            year: 2016,
+            short_term: 500.0,
+            long_term: 0.0,
+        };
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let years = schedule_d_by_year(&tax_events, Some(&opening), FILING_STATUS_SINGLE, &utc);
+
+        assert_eq!(years.iter().map(|y| y.year).collect::<Vec<i32>>(), vec![2017, 2018, 2019]);
+        assert_eq!(years[0].net_long_term, -10000.0);
+        assert_eq!(years[0].short_term_carryforward, 0.0);
+        assert_eq!(years[0].long_term_carryforward, 7500.0);
+        assert_eq!(years[1].long_term_carryforward, 4500.0);
+        assert_eq!(years[2].short_term_gain, 1000.0);
+        assert_eq!(years[2].loss_deduction, 3000.0);
+        assert_eq!(years[2].long_term_carryforward, 500.0);
+    }
+
+    #[test]
+    fn harvest_losses_in_method_order() {
+        let mut btc = Account::new("BTC".to_string(), 0.0);
+        btc.deposit("a", Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap(), 1.0, 1000.0, vec![]);
+        btc.deposit("b", Utc.with_ymd_and_hms(2017, 6, 1, 0, 0, 0).unwrap(), 1.0, 5000.0, vec![]);
+        btc.deposit("c", Utc.with_ymd_and_hms(2017, 12, 20, 0, 0, 0).unwrap(), 1.0, 2000.0, vec![]);
+        let accounts = hashmap! {"BTC".to_string() => btc};
+        let prices = hashmap! {"BTC".to_string() => 3000.0};
+        let datetime = Utc.with_ymd_and_hms(2017, 12, 31, 0, 0, 0).unwrap();
+
+        // FIFO sells the lot with a 2000 gain before the lot with a 2000 loss.
+        let fifo = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO);
+        let (lots, assets) = find_harvest_opportunities(&accounts, &prices, datetime, &fifo, LOSS_RULE_WASH_SALE);
+        assert!(lots.is_empty() && assets.is_empty());
+
+        let (lots, assets) = find_harvest_opportunities(&accounts, &prices, datetime, &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_LIFO), LOSS_RULE_WASH_SALE);
+        assert_eq!(lots.iter().map(|l| l.buy_transaction_id.as_str()).collect::<Vec<&str>>(), vec!["c", "b"]);
+        assert_eq!(assets[0].realizable_loss, 1000.0);
+        assert_eq!(assets[0].flagged_loss, 0.0);
+
+        // A LIFO election for BTC applies over the FIFO default, and dust left of a lot is not a replacement.
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let mut elected = fifo.clone();
+        elected.method_schedule = Some(MethodSchedule::new(
+            vec![MethodElection {
+                method: TAX_ACCOUNTING_METHOD_LIFO.to_string(),
+                from: None,
+                to: None,
+                asset: Some("BTC".to_string()),
+                wallet: None,
+            }],
+            &utc,
+        ));
+        let mut dusty_accounts = accounts.clone();
+        dusty_accounts.get_mut("BTC").unwrap().deposit("d", Utc.with_ymd_and_hms(2017, 12, 25, 0, 0, 0).unwrap(), 1e-12, 0.0, vec![]);
+        let (lots, assets) = find_harvest_opportunities(&dusty_accounts, &prices, datetime, &elected, LOSS_RULE_WASH_SALE);
+        assert_eq!(lots.iter().map(|l| l.buy_transaction_id.as_str()).collect::<Vec<&str>>(), vec!["c", "b"]);
+        assert_eq!(assets[0].flagged_loss, 0.0);
+
+        // The lot bought 11 days before is kept, which triggers both rules.
+        let (lots, assets) = find_harvest_opportunities(&accounts, &prices, datetime, &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_HIFO), LOSS_RULE_SUPERFICIAL_LOSS);
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].buy_transaction_id, "b");
+        assert!(lots[0].loss_rule_warning.contains("transaction c"));
+        assert_eq!(assets[0].quantity_to_sell, 1.0);
+        assert_eq!(assets[0].flagged_loss, 2000.0);
+
+        let summary = summarize_harvest(&[], &assets, FILING_STATUS_MARRIED_FILING_SEPARATELY);
+        assert_eq!(summary.offset_gains, 0.0);
+        assert_eq!(summary.offset_ordinary_income, 1500.0);
+        assert_eq!(summary.carried_forward, 500.0);
+    }
+
+    #[test]
+    fn simulate_sale_without_changing_accounts() {
+        let (real_tax_events, accounts) = calculate_capital_gains(&mut test_transactions_eth_buy2_sell1(), TAX_ACCOUNTING_METHOD_FIFO);
+        let mut sale = test_transactions_eth_buy2_sell1().remove(2);
+        sale.id = "what-if".to_string();
+        sale.datetime = Utc.with_ymd_and_hms(2017, 4, 1, 0, 0, 0).unwrap();
+        sale.origin_quantity = 2.0;
+        sale.usd_value = Some(8000.0);
+
+        let mut back_dated = sale.clone();
+        back_dated.id = "back-dated".to_string();
+        back_dated.datetime = *DATE_TIME1;
+
+        let simulation = simulate_transactions(&accounts, Some(*DATE_TIME2), &mut [sale, back_dated], &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_LIFO));
+        assert_eq!(simulation.back_dated_transactions, vec!["back-dated"]);
+        assert_eq!(simulation.short_transactions, vec!["what-if"]);
+        assert_eq!(simulation.tax_events.len(), 1);
+        assert_eq!(simulation.tax_events[0].buy_transaction_id, "1");
+        assert_eq!(simulation.tax_events[0].gain, 1500.0);
+        assert_eq!(accounts["ETH"].balance, 1.0);
+
+        let rates = TaxRates {
+            short_term: 0.2,
+            long_term: 0.1,
+        };
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let impact = simulated_tax_impact(&real_tax_events, &[], &simulation.tax_events, &rates, &utc);
+        assert_eq!(impact.len(), 1);
+        assert_eq!(impact[0].year, 2017);
+        assert_eq!(impact[0].tax, 300.0);
+    }
+
+    #[test]
+    fn raise_cash_for_least_tax() {
+        let mut btc = Account::new("BTC".to_string(), 0.0);
+        btc.deposit("a", Utc.with_ymd_and_hms(2016, 1, 1, 0, 0, 0).unwrap(), 1.0, 1000.0, vec![]);
+        btc.deposit("b", Utc.with_ymd_and_hms(2017, 6, 1, 0, 0, 0).unwrap(), 1.0, 5000.0, vec![]);
+        btc.deposit("c", Utc.with_ymd_and_hms(2017, 12, 20, 0, 0, 0).unwrap(), 1.0, 2000.0, vec![]);
+        let accounts = hashmap! {"BTC".to_string() => btc};
+        let prices = hashmap! {"BTC".to_string() => 3000.0};
+        let datetime = Utc.with_ymd_and_hms(2017, 12, 31, 0, 0, 0).unwrap();
+
+        // The loss first, then the short term gain of 8 cents of tax per dollar before the long term gain of 10 cents.
+        let specific_id = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_SPECIFIC_ID);
+        let nothing_realized = RealizedYear {
+            year: 2017,
+            ..Default::default()
+        };
+        let plan = plan_cash_raise(&accounts, &prices, datetime, 5000.0, &nothing_realized, &specific_id, &TaxRates::default());
+        assert_eq!(plan.sales.iter().map(|s| s.buy_transaction_id.as_str()).collect::<Vec<&str>>(), vec!["b", "c"]);
+        assert!((plan.sales[1].quantity - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(plan.raised, 5000.0);
+        assert_eq!(plan.short_term_gain, -1333.33);
+        assert_eq!(plan.estimated_tax, 0.0);
+
+        let plan = plan_cash_raise(&accounts, &prices, datetime, 10000.0, &nothing_realized, &specific_id, &TaxRates::default());
+        assert_eq!(plan.sales.len(), 3);
+        assert_eq!(plan.shortfall, 1000.0);
+        assert_eq!(plan.long_term_gain, 2000.0);
+
+        // With FIFO the lots can only be sold in the order they were bought.
+        let plan = plan_cash_raise(&accounts, &prices, datetime, 5000.0, &nothing_realized, &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO), &TaxRates::default());
+        assert_eq!(plan.sales.iter().map(|s| s.buy_transaction_id.as_str()).collect::<Vec<&str>>(), vec!["a", "b"]);
+
+        // Against a short term gain already realized in the year, the loss of lot b saves 24 cents per dollar.
+        let realized = RealizedYear {
+            year: 2017,
+            short_term_gain: 4000.0,
+            ..Default::default()
+        };
+        let plan = plan_cash_raise(&accounts, &prices, datetime, 5000.0, &realized, &specific_id, &TaxRates::default());
+        assert_eq!(plan.sales.iter().map(|s| (s.buy_transaction_id.as_str(), s.estimated_tax)).collect::<Vec<(&str, f64)>>(), vec![("b", -480.0), ("c", 160.0)]);
+        assert_eq!(plan.estimated_tax, -320.0);
+    }
+
+    #[test]
+    fn method_elections_by_date_and_wallet() {
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let election = |method: &str, from: Option<NaiveDate>, wallet: Option<&str>| MethodElection {
+            method: method.to_string(),
+            from,
+            to: None,
+            asset: Some("ETH".to_string()),
+            wallet: wallet.map(|w| w.to_string()),
+        };
+        let mut options = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO);
+        options.method_schedule = Some(MethodSchedule::new(vec![election(TAX_ACCOUNTING_METHOD_HIFO, NaiveDate::from_ymd_opt(2017, 2, 15), None)], &utc));
+        let (tax_events, _) = calculate_capital_gains_with_accounts(&mut test_transactions_eth_buy2_sell1(), default_accounts(), &options);
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!(tax_events[0].buy_transaction_id, "1");
+        assert_eq!(tax_events[0].method, TAX_ACCOUNTING_METHOD_HIFO);
+
+        // The wallet election wins over the asset one, the lots it doesn't designate are sold with -m.
+        options.method_schedule = Some(MethodSchedule::new(
+            vec![
+                election(TAX_ACCOUNTING_METHOD_SPECIFIC_ID, None, Some(WALLET_KRAKEN)),
+                election(TAX_ACCOUNTING_METHOD_HIFO, NaiveDate::from_ymd_opt(2017, 2, 15), None),
+            ],
+            &utc,
+        ));
+        options.lot_designations = hashmap! {"2".to_string() => vec![LotDesignation {
+            sell_transaction_id: "2".to_string(),
+            buy_transaction_id: "1".to_string(),
+            quantity: 0.25,
+        }]};
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut test_transactions_eth_buy2_sell1(), default_accounts(), &options);
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!((tax_events[0].buy_transaction_id.as_str(), tax_events[0].method.as_str()), ("1", TAX_ACCOUNTING_METHOD_SPECIFIC_ID));
+        assert_eq!(tax_events[0].gain, 125.0);
+        assert_eq!((tax_events[1].buy_transaction_id.as_str(), tax_events[1].method.as_str()), ("0", TAX_ACCOUNTING_METHOD_FIFO));
+        assert_eq!(tax_events[1].gain, 562.5);
+        assert_eq!(accounts["ETH"].balance, 1.0);
+    }
+
+    #[test]
+    fn unhonored_designations_are_reported() {
+        let mut account = Account::new("ETH".to_string(), 0.0);
+        account.deposit("0", Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap(), 1.0, 1000.0, vec![]);
+        account.deposit("1", Utc.with_ymd_and_hms(2017, 2, 1, 0, 0, 0).unwrap(), 1.0, 2000.0, vec![]);
+        let datetime = Utc.with_ymd_and_hms(2017, 1, 15, 0, 0, 0).unwrap();
+        let (lots, unhonored) = account.withdraw_designated(datetime, 1.0, &[("1", 0.5), ("x", 0.5), ("0", 0.25)], None, false);
+        assert_eq!(lots.len(), 1);
+        assert_eq!((lots[0].transaction_id.as_str(), lots[0].quantity), ("0", 0.25));
+        assert_eq!(unhonored.iter().map(|u| (u.buy_transaction_id.as_str(), u.reason)).collect::<Vec<(&str, &str)>>(), vec![("1", "not spendable yet"), ("x", "unknown")]);
+
+        let (lots, unhonored) = account.withdraw_designated(datetime, 1.0, &[("0", 1.0)], None, false);
+        assert_eq!(lots[0].quantity, 0.75);
+        assert_eq!((unhonored[0].reason, unhonored[0].quantity), ("exhausted", 0.25));
+    }
+
+    #[test]
+    fn lots_tracked_per_wallet_after_allocation() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        let mut transfer = transactions[1].clone();
+        transfer.id = "transfer".to_string();
+        transfer.datetime = Utc.with_ymd_and_hms(2017, 2, 15, 0, 0, 0).unwrap();
+        transfer.origin_wallet = WALLET_KRAKEN.to_string();
+        transfer.origin_asset = "ETH".to_string();
+        transfer.origin_quantity = 1.0;
+        transfer.destination_wallet = "Ledger".to_string();
+        transactions.push(transfer);
+        let cutoff = Utc.with_ymd_and_hms(2017, 2, 20, 0, 0, 0).unwrap();
+
+        let mut held_transactions = transactions.iter().filter(|t| t.datetime < cutoff).cloned().collect::<Vec<Transaction>>();
+        let (_, held_accounts) = calculate_capital_gains(&mut held_transactions, TAX_ACCOUNTING_METHOD_FIFO);
+        let balances = wallet_balances(&held_transactions);
+        assert_eq!(balances[&("ETH".to_string(), "Ledger".to_string())], 1.0);
+        let (allocations, warnings) = propose_lot_allocation(&held_accounts, &balances, TAX_ACCOUNTING_METHOD_FIFO);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            allocations.iter().map(|a| (a.wallet.as_str(), a.buy_transaction_id.as_str())).collect::<Vec<(&str, &str)>>(),
+            vec![(WALLET_KRAKEN, "0"), ("Ledger", "1")]
+        );
+
+        // Pooled, LIFO sells the lot bought last, which was moved to the Ledger wallet.
+        let (tax_events, _) = calculate_capital_gains(&mut transactions.clone(), TAX_ACCOUNTING_METHOD_LIFO);
+        assert_eq!(tax_events[0].buy_transaction_id, "1");
+        let mut options = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_LIFO);
+        options.wallet_allocation = Some(WalletAllocation::new(cutoff, allocations.clone()));
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions.clone(), default_accounts(), &options);
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!(tax_events[0].buy_transaction_id, "0");
+        assert_eq!(tax_events[0].gain, 750.0);
+        assert_eq!(accounts["ETH"].balance, 1.0);
+        assert_eq!(accounts["ETH"].open_lots_in_order(TAX_ACCOUNTING_METHOD_FIFO)[0].wallet.as_deref(), Some("Ledger"));
+
+        // A transfer back with a fee of 0.1 ETH splits lot 1 between the wallets, the designation of lot 1 for a sale
+        // from Kraken sells the part held in Kraken. The lineage still shows the whole lot.
+        let mut transfer_back = transactions[2].clone();
+        transfer_back.id = "transfer back".to_string();
+        transfer_back.datetime = Utc.with_ymd_and_hms(2017, 3, 5, 0, 0, 0).unwrap();
+        (transfer_back.origin_wallet, transfer_back.destination_wallet) = ("Ledger".to_string(), WALLET_KRAKEN.to_string());
+        transfer_back.destination_asset = "ETH".to_string();
+        (transfer_back.origin_quantity, transfer_back.destination_quantity) = (0.5, 0.4);
+        let mut sale = transactions[2].clone();
+        sale.id = "3".to_string();
+        sale.datetime = Utc.with_ymd_and_hms(2017, 3, 10, 0, 0, 0).unwrap();
+        (sale.origin_quantity, sale.destination_quantity, sale.usd_value) = (0.4, 1200.0, Some(1200.0));
+        transactions.extend(vec![transfer_back, sale]);
+        let mut options = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_SPECIFIC_ID);
+        options.wallet_allocation = Some(WalletAllocation::new(cutoff, allocations));
+        let designation = |sell: &str, buy: &str, quantity: f64| LotDesignation {
+            sell_transaction_id: sell.to_string(),
+            buy_transaction_id: buy.to_string(),
+            quantity,
+        };
+        options.lot_designations = hashmap! {
+            "2".to_string() => vec![designation("2", "0", 1.0)],
+            "3".to_string() => vec![designation("3", "1", 0.4)],
+        };
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions, default_accounts(), &options);
+        assert_eq!(tax_events.len(), 2);
+        assert_eq!((tax_events[1].buy_transaction_id.as_str(), tax_events[1].method.as_str(), tax_events[1].cost_basis), ("1", TAX_ACCOUNTING_METHOD_SPECIFIC_ID, 1000.0));
+        let lots = accounts["ETH"].open_lots_in_order(TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!(lots.iter().map(|lot| (lot.wallet.as_deref(), lot.remaining_quantity)).collect::<Vec<(Option<&str>, f64)>>(), vec![(Some("Ledger"), 0.5)]);
+        assert_eq!(LotIndex::new(&accounts).lineage(1, &tax_events[1])[0].quantity, 1.0);
+    }
+
+    #[test]
+    fn allocation_used_up_by_lots_sharing_an_id() {
+        let datetime = Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap();
+        let mut account = Account::new("ETH".to_string(), 0.0);
+        account.deposit("0", datetime, 1.0, 1000.0, vec![]);
+        account.deposit("0", datetime, 1.0, 2000.0, vec![]);
+        account.allocate_to_wallets(&[("0", "Ledger", 1.5)]);
+
+        // The first lot takes 1 of the 1.5 allocated to the Ledger wallet, the second one the 0.5 left.
+        let lots = account.open_lots_in_order(TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!(
+            lots.iter().map(|lot| (lot.wallet.as_deref(), lot.remaining_quantity, lot.usd_value)).collect::<Vec<(Option<&str>, f64, f64)>>(),
+            vec![(Some("Ledger"), 1.0, 1000.0), (Some("Ledger"), 0.5, 1000.0), (None, 0.5, 1000.0)]
+        );
+        assert_eq!(account.balance, 2.0);
+    }
+
+    #[test]
+    fn margin_positions_realized_on_close() {
+        let trade = |id: &str, day: u32, type_: &str, quantity: f64, usd_value: f64, usd_fee: f64| MarginTransaction {
+            id: id.to_string(),
+            datetime: Utc.with_ymd_and_hms(2017, 3, day, 0, 0, 0).unwrap(),
+            asset: "XBT".to_string(),
+            type_: type_.to_string(),
+            quantity,
+            usd_value,
+            usd_fee: Some(usd_fee),
+            position_id: None,
+        };
+        let fee = |id: &str, day: u32, position_id: Option<&str>, usd_fee: f64| MarginTransaction {
+            position_id: position_id.map(|p| p.to_string()),
+            ..trade(id, day, MARGIN_TYPE_ROLLOVER, 0.0, 0.0, usd_fee)
+        };
+        // The rollover fee of the long lot is realized by the two closes of that lot, the fee of the short lot
+        // by its cover, the fee charged once the short lot is covered is added to that gain and the fee without
+        // a position is realized by the next close.
+        let mut transactions = vec![
+            trade("open", 1, MARGIN_TYPE_BUY, 1.0, 10000.0, 10.0),
+            fee("rollover", 2, Some("open"), 5.0),
+            trade("half", 3, MARGIN_TYPE_SELL, 0.5, 6000.0, 6.0),
+            trade("reverse", 4, MARGIN_TYPE_SELL, 1.0, 11000.0, 11.0),
+            fee("funding", 4, Some("reverse"), 2.0),
+            fee("unknown", 4, None, 0.5),
+            trade("cover", 5, MARGIN_TYPE_BUY, 0.5, 5000.0, 5.0),
+            fee("closing", 5, Some("cover"), 1.0),
+        ];
+
+        let (gains, positions) = calculate_margin_gains(&mut transactions);
+        assert_eq!(
+            gains.iter().map(|g| (g.side.as_str(), g.close_transaction_id.as_str(), g.fees, g.gain)).collect::<Vec<(&str, &str, f64, f64)>>(),
+            vec![
+                (MARGIN_SIDE_LONG, "half", 13.5, 986.5),
+                (MARGIN_SIDE_LONG, "reverse", 13.0, 487.0),
+                (MARGIN_SIDE_SHORT, "cover", 14.0, 486.0),
+            ]
+        );
+        assert!(positions["XBT"].lots.is_empty());
+    }
+
+    #[test]
+    fn futures_marked_to_market_at_year_end() {
+        let fill = |id: &str, datetime: DateTime<Utc>, type_: &str, quantity: f64, price: f64| FuturesFill {
+            id: id.to_string(),
+            datetime,
+            contract: "BTCH18".to_string(),
+            type_: type_.to_string(),
+            quantity,
+            price,
+            multiplier: Some(5.0),
+            usd_fee: Some(10.0),
+        };
+        let fills = vec![
+            fill("open", Utc.with_ymd_and_hms(2017, 12, 18, 0, 0, 0).unwrap(), FUTURES_TYPE_BUY, 2.0, 19000.0),
+            fill("close", Utc.with_ymd_and_hms(2018, 1, 10, 0, 0, 0).unwrap(), FUTURES_TYPE_SELL, 1.0, 15000.0),
+        ];
+        let settlements = vec![
+            FuturesSettlement {
+                contract: "BTCH18".to_string(),
+                datetime: Utc.with_ymd_and_hms(2017, 12, 29, 21, 0, 0).unwrap(),
+                price: 14000.0,
+                final_settlement: None,
+            },
+            FuturesSettlement {
+                contract: "BTCH18".to_string(),
+                datetime: Utc.with_ymd_and_hms(2018, 3, 29, 21, 0, 0).unwrap(),
+                price: 7000.0,
+                final_settlement: Some(true),
+            },
+        ];
+        let utc = FixedOffset::east_opt(0).unwrap();
+
+        let as_of = Utc.with_ymd_and_hms(2019, 1, 1, 0, 0, 0).unwrap();
+        let contracts = calculate_section_1256(&fills, &settlements, None, PRICE_INTERPOLATION_PREVIOUS, &utc, as_of);
+        assert_eq!(contracts.len(), 2);
+        assert_eq!((contracts[0].year, contracts[0].realized, contracts[0].marked_to_market, contracts[0].gain), (2017, 0.0, -50000.0, -50010.0));
+        // The 2018 gains start from the year end mark of 14000.
+        assert_eq!((contracts[1].year, contracts[1].realized, contracts[1].marked_to_market, contracts[1].gain), (2018, -30000.0, 0.0, -30010.0));
+
+        let years = form_6781_by_year(&contracts);
+        assert_eq!(years[0].long_term_gain, -30006.0);
+        assert_eq!(years[0].short_term_gain, -20004.0);
+
+        // A position still open in 2018 is marked at the end of 2018 only once 2018 is over at `as_of`.
+        let mut fills = fills;
+        fills.push(FuturesFill {
+            contract: "BTCM18".to_string(),
+            ..fill("reopen", Utc.with_ymd_and_hms(2018, 4, 2, 0, 0, 0).unwrap(), FUTURES_TYPE_BUY, 1.0, 8000.0)
+        });
+        let mut settlements = settlements;
+        settlements.push(FuturesSettlement {
+            contract: "BTCM18".to_string(),
+            datetime: Utc.with_ymd_and_hms(2018, 12, 28, 21, 0, 0).unwrap(),
+            price: 6000.0,
+            final_settlement: None,
+        });
+        let marked_to_market = |as_of: DateTime<Utc>| {
+            let contracts = calculate_section_1256(&fills, &settlements, None, PRICE_INTERPOLATION_PREVIOUS, &utc, as_of);
+            contracts.iter().find(|c| c.contract == "BTCM18").map(|c| c.marked_to_market)
+        };
+        assert_eq!(marked_to_market(Utc.with_ymd_and_hms(2018, 7, 1, 0, 0, 0).unwrap()), Some(0.0));
+        assert_eq!(marked_to_market(as_of), Some(-10000.0));
+    }
+
+    #[test]
+    fn wrapping_carries_lots_over() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions.remove(1);
+        let mut wrap = transactions[1].clone();
+        wrap.id = "wrap".to_string();
+        wrap.datetime = *DATE_TIME1;
+        wrap.destination_wallet = WALLET_KRAKEN.to_string();
+        wrap.destination_asset = "WETH".to_string();
+        wrap.destination_quantity = 1.0;
+        wrap.usd_value = Some(2500.0);
+        transactions.insert(1, wrap);
+        transactions[2].origin_asset = "WETH".to_string();
+
+        let rule = |non_taxable: bool| EquivalenceRule {
+            asset: "ETH".to_string(),
+            equivalent_asset: "WETH".to_string(),
+            non_taxable,
+        };
+        let mut options = ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO);
+        options.equivalence_rules = Some(EquivalenceRules::new(vec![rule(true)]));
+        let (tax_events, accounts) = calculate_capital_gains_with_accounts(&mut transactions.clone(), default_accounts(), &options);
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!((tax_events[0].buy_transaction_id.as_str(), tax_events[0].buy_date), ("0", *DATE_TIME0));
+        assert_eq!(tax_events[0].gain, 750.0);
+        assert_eq!(accounts["ETH"].balance, 0.0);
+        assert_eq!(accounts["WETH"].balance, 0.0);
+        // The sold WETH lot is the ETH lot carried over, with the same transaction id.
+        let lineage = LotIndex::new(&accounts).lineage(0, &tax_events[0]);
+        assert_eq!(lineage.iter().map(|step| (step.transaction_id.as_str(), step.asset.as_str(), step.usd_value)).collect::<Vec<_>>(), vec![("0", "WETH", 2250.0)]);
+
+        // The wrap is posted at the cost basis of the lot, 2250, not at its value of 2500.
+        let ledger = post_transactions(&mut transactions.clone(), default_accounts(), &options);
+        assert!(ledger.is_balanced());
+        assert!(ledger.unbalanced_entries().is_empty());
+        let trial_balance = ledger.trial_balance();
+        let balance = |account: &str| trial_balance.iter().find(|row| row.account == account).unwrap().balance;
+        assert_eq!((balance("Assets:Crypto:ETH"), balance("Assets:Crypto:WETH")), (0.0, 0.0));
+        assert_eq!(balance(LEDGER_ACCOUNT_REALIZED_GAINS), -750.0);
+
+        options.equivalence_rules = Some(EquivalenceRules::new(vec![rule(false)]));
+        let (tax_events, _) = calculate_capital_gains_with_accounts(&mut transactions, default_accounts(), &options);
+        assert_eq!(tax_events.iter().map(|e| e.gain).collect::<Vec<f64>>(), vec![250.0, 500.0]);
+    }
+
+    #[test]
+    fn migration_converts_lots_at_ratio() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        let mut migration = transactions[1].clone();
+        migration.id = "migration".to_string();
+        migration.datetime = Utc.with_ymd_and_hms(2017, 2, 15, 0, 0, 0).unwrap();
+        migration.origin_wallet = WALLET_KRAKEN.to_string();
+        migration.origin_asset = "ETH".to_string();
+        migration.origin_quantity = 2.0;
+        migration.destination_asset = "NEWETH".to_string();
+        migration.destination_quantity = 200.0;
+        migration.usd_value = None;
+        migration.type_ = Some(TRANSACTION_TYPE_MIGRATION.to_string());
+        transactions.insert(2, migration);
+        transactions[3].origin_asset = "NEWETH".to_string();
+        transactions[3].origin_quantity = 100.0;
+        // The migration needs no price.
+        assert!(fill_missing_usd_values(&mut transactions, &PriceDatabase::new(), PRICE_INTERPOLATION_PREVIOUS).is_empty());
+
+        let (tax_events, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!(tax_events.len(), 1);
+        assert_eq!((tax_events[0].buy_transaction_id.as_str(), tax_events[0].buy_date), ("0", *DATE_TIME0));
+        assert_eq!(tax_events[0].gain, 750.0);
+        assert_eq!(accounts["ETH"].balance, 0.0);
+        assert_eq!(accounts["NEWETH"].balance, 100.0);
+        let lots = accounts["NEWETH"].open_lots_in_order(TAX_ACCOUNTING_METHOD_FIFO);
+        assert_eq!((lots[0].datetime, lots[0].remaining_quantity, lots[0].usd_value), (*DATE_TIME1, 100.0, 2500.0));
+
+        // The ledger moves the cost basis of the lots, 4750, from ETH to NEWETH.
+        let ledger = post_transactions(&mut transactions, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_FIFO));
+        assert!(ledger.unbalanced_entries().is_empty());
+        let trial_balance = ledger.trial_balance();
+        let balance = |account: &str| trial_balance.iter().find(|row| row.account == account).unwrap().balance;
+        assert_eq!((balance("Assets:Crypto:ETH"), balance("Assets:Crypto:NEWETH")), (0.0, 2500.0));
+        let migration = ledger.entries.iter().find(|e| e.transaction_id == "migration").unwrap();
+        assert_eq!((migration.debits(), migration.credits()), (4750.0, 4750.0));
+    }
+
+    #[test]
+    fn streaming_matches_in_memory() {
+        let mut transactions = benchmark_transactions(1000);
+        let (tax_events, accounts) = calculate_capital_gains(&mut transactions.clone(), TAX_ACCOUNTING_METHOD_HIFO);
+
+        let dir = std::env::temp_dir().join("ptt_streaming_test");
+        fs::create_dir_all(&dir).unwrap();
+        let input_file = dir.join("transactions.csv");
+        let mut writer = csv::Writer::from_path(&input_file).unwrap();
+        transactions.reverse();
+        for transaction in transactions.iter() {
+            writer.serialize(transaction).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let output = StreamOutput {
+            long_gains_file: &dir.join("long_gains.csv"),
+            short_gains_file: &dir.join("short_gains.csv"),
+            income_file: &dir.join("income.csv"),
+            period: None,
+        };
+        let sorted = read_transactions_with_external_sort(input_file.to_str().unwrap(), 64).expect("the file to be sorted");
+        let (streamed_accounts, summary) = process_transactions_stream(sorted, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_HIFO), &output, 64).expect("the transactions to be processed");
+
+        assert_eq!(summary.transactions, 1000);
+        assert_eq!(summary.tax_events, tax_events.len());
+        let mut streamed_gain = 0.0;
+        for file in [output.long_gains_file, output.short_gains_file].iter() {
+            let mut reader = csv::Reader::from_path(file).unwrap();
+            // A file without tax events has no header either.
+            let gain_column = match reader.headers().unwrap().iter().position(|h| h == "gain") {
+                Some(gain_column) => gain_column,
+                None => continue,
+            };
+            for record in reader.records() {
+                streamed_gain += record.unwrap()[gain_column].parse::<f64>().unwrap();
+            }
+        }
+        let gain: f64 = tax_events.iter().map(|e| e.gain).sum();
+        assert!((streamed_gain - gain).abs() < 0.01);
+
+        let btc = &streamed_accounts["BTC"];
+        assert!((btc.balance - accounts["BTC"].balance).abs() < 1e-9);
+        assert!(btc.deposits.len() < accounts["BTC"].deposits.len());
+
+        // Out of order input is rejected when the external sort is skipped.
+        let unsorted = read_sorted_transactions(input_file.to_str().unwrap()).unwrap();
+        assert!(process_transactions_stream(unsorted, default_accounts(), &ProcessingOptions::new(TAX_ACCOUNTING_METHOD_HIFO), &output, 64).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn save_transactions_and_tax_events() {
         let mut transactions = test_transactions_eth_buy2_sell1();
         let (tax_events, accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_FIFO);
 
-        save_transactions_and_tax_events_to_file(&transactions, &tax_events, &accounts, "transactions_and_tax_events_test.csv").expect("the file to be saved");
+        save_transactions_and_tax_events_to_file(&transactions, &tax_events, &accounts, "transactions_and_tax_events_test.csv", false).expect("the file to be saved");
+    }
+
+    #[test]
+    fn transactions_joined_with_their_tax_events() {
+        let mut transactions = test_transactions_eth_buy2_sell1();
+        transactions[2].origin_quantity = 2.0;
+        transactions.push(Transaction {
+            id: "3".to_string(),
+            datetime: *DATE_TIME2,
+            origin_wallet: WALLET_BANK.to_string(),
+            origin_asset: "USD".to_string(),
+            origin_quantity: 100.0,
+            destination_wallet: WALLET_KRAKEN.to_string(),
+            destination_asset: "DOGE".to_string(),
+            destination_quantity: 1000.0,
+            usd_value: Some(100.0),
+            usd_fee: None,
+            fee_asset: None,
+            sequence: None,
+            type_: None,
+        });
+        let (tax_events, mut accounts) = calculate_capital_gains(&mut transactions, TAX_ACCOUNTING_METHOD_FIFO);
+        // The DOGE lot is split between two wallets and the rest, and a lot of another asset shares its id.
+        accounts.get_mut("DOGE").unwrap().allocate_to_wallets(&[("3", WALLET_KRAKEN, 400.0), ("3", "Ledger", 100.0)]);
+        let mut wrapped = Account::new("WDOGE".to_string(), 0.0);
+        wrapped.deposit("3", *DATE_TIME2, 7.0, 1.0, vec![]);
+        accounts.insert("WDOGE".to_string(), wrapped);
+        let out_file = std::env::temp_dir().join("ptt_transactions_and_tax_events_test.csv");
+        let read_rows = |aggregate| {
+            save_transactions_and_tax_events_to_file(&transactions, &tax_events, &accounts, out_file.to_str().unwrap(), aggregate).expect("the file to be saved");
+            csv::Reader::from_path(&out_file).unwrap().records().map(|r| r.unwrap()).collect::<Vec<csv::StringRecord>>()
+        };
+
+        let rows = read_rows(false);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(&rows[0][8], "0");
+        assert_eq!(&rows[2][12], "0");
+        assert_eq!(&rows[3][12], "1");
+        assert_eq!(&rows[4][8], "1000");
+
+        let rows = read_rows(true);
+        assert_eq!(rows.len(), 4);
+        assert_eq!(&rows[2][12], "0 1");
+        assert_eq!(&rows[2][17], "-1750");
     }
 }
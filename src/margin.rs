@@ -0,0 +1,199 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use tax::round_to_dollars;
+use unrealized::DUST_QUANTITY;
+
+pub const MARGIN_TYPE_BUY: &str = "buy";
+pub const MARGIN_TYPE_SELL: &str = "sell";
+/// A rollover, funding or margin opening fee of a position, with a zero quantity.
+pub const MARGIN_TYPE_ROLLOVER: &str = "rollover";
+
+pub const MARGIN_SIDE_LONG: &str = "long";
+pub const MARGIN_SIDE_SHORT: &str = "short";
+
+/// A leveraged trade or a fee of a margin position. Margin trades don't move coins between wallets, so they are
+/// kept out of the spot transactions and their accounts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MarginTransaction {
+    pub id: String,
+    pub datetime: DateTime<Utc>,
+    pub asset: String,
+    /// `buy`, `sell` or `rollover`.
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub quantity: f64,
+    /// USD value of the quantity traded, without the fee.
+    pub usd_value: f64,
+    pub usd_fee: Option<f64>,
+    /// For a fee, the id of the trade whose position it belongs to. Optional column.
+    #[serde(default)]
+    pub position_id: Option<String>,
+}
+
+/// The open part of a margin trade.
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionLot {
+    pub asset: String,
+    pub side: String,
+    pub transaction_id: String,
+    pub datetime: DateTime<Utc>,
+    pub quantity: f64,
+    pub usd_value: f64,
+    /// The part of the opening fee and of the fees charged on the lot that belongs to the open quantity.
+    pub fee: f64,
+}
+
+/// The open lots of the margin position of an asset, all on the same side, and the fees without a position
+/// that are not realized yet.
+#[derive(Debug, Default)]
+pub struct MarginPosition {
+    pub lots: VecDeque<PositionLot>,
+    pub pending_fees: f64,
+}
+
+/// The profit or loss of closing a margin position, or part of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarginGain {
+    pub asset: String,
+    pub side: String,
+    pub quantity: f64,
+    pub open_transaction_id: String,
+    pub close_transaction_id: String,
+    pub open_date: DateTime<Utc>,
+    pub close_date: DateTime<Utc>,
+    pub open_value: f64,
+    pub close_value: f64,
+    /// Trading fees of both trades and the other fees of the position realized by the close.
+    pub fees: f64,
+    pub gain: f64,
+}
+
+pub fn read_margin_transactions(file_path: &str) -> Result<Vec<MarginTransaction>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut transactions = vec![];
+    for transaction in reader.deserialize() {
+        transactions.push(transaction?);
+    }
+    Ok(transactions)
+}
+
+/// Replays the margin transactions by datetime. A trade opposite to the open position closes its lots first in,
+/// first out and realizes their profit or loss; what is left of the trade opens a position on its own side.
+/// A fee is added to the open lot of its `position_id`, and realized with it, or to the last gain of a position
+/// already closed. A fee without a position, or whose position is unknown, is realized by the next close of the
+/// asset's position.
+pub fn calculate_margin_gains(transactions: &mut [MarginTransaction]) -> (Vec<MarginGain>, BTreeMap<String, MarginPosition>) {
+    transactions.sort_by_key(|t| t.datetime);
+
+    let mut gains: Vec<MarginGain> = vec![];
+    let mut positions: BTreeMap<String, MarginPosition> = BTreeMap::new();
+    for transaction in transactions.iter() {
+        let position = positions.entry(transaction.asset.clone()).or_default();
+        let fee = transaction.usd_fee.unwrap_or_default();
+        if transaction.type_ == MARGIN_TYPE_ROLLOVER {
+            let position_id = match transaction.position_id.as_deref() {
+                Some(position_id) => position_id,
+                None => {
+                    position.pending_fees += fee;
+                    continue;
+                }
+            };
+            if let Some(lot) = position.lots.iter_mut().find(|lot| lot.transaction_id == position_id) {
+                lot.fee += fee;
+            } else if let Some(gain) = gains
+                .iter_mut()
+                .rev()
+                .find(|g| g.asset == transaction.asset && (g.open_transaction_id == position_id || g.close_transaction_id == position_id))
+            {
+                gain.fees = round_to_dollars(gain.fees + fee);
+                gain.gain = round_to_dollars(gain.gain - fee);
+            } else {
+                eprintln!("margin transaction {}: no position {}, the fee is realized by the next close", transaction.id, position_id);
+                position.pending_fees += fee;
+            }
+            continue;
+        }
+
+        let side = if transaction.type_ == MARGIN_TYPE_BUY {
+            MARGIN_SIDE_LONG
+        } else if transaction.type_ == MARGIN_TYPE_SELL {
+            MARGIN_SIDE_SHORT
+        } else {
+            eprintln!("margin transaction {}: unknown type {}, ignored", transaction.id, transaction.type_);
+            continue;
+        };
+        let mut quantity = transaction.quantity;
+        while quantity > DUST_QUANTITY && position.lots.front().is_some_and(|lot| lot.side != side) {
+            let lot = position.lots.front_mut().unwrap();
+            let closed_quantity = lot.quantity.min(quantity);
+            let open_value = lot.usd_value * (closed_quantity / lot.quantity);
+            let open_fee = lot.fee * (closed_quantity / lot.quantity);
+            let close_value = transaction.usd_value * (closed_quantity / transaction.quantity);
+            let fees = open_fee + fee * (closed_quantity / transaction.quantity) + position.pending_fees;
+            let profit = if lot.side == MARGIN_SIDE_LONG { close_value - open_value } else { open_value - close_value };
+            gains.push(MarginGain {
+                asset: transaction.asset.clone(),
+                side: lot.side.clone(),
+                quantity: closed_quantity,
+                open_transaction_id: lot.transaction_id.clone(),
+                close_transaction_id: transaction.id.clone(),
+                open_date: lot.datetime,
+                close_date: transaction.datetime,
+                open_value: round_to_dollars(open_value),
+                close_value: round_to_dollars(close_value),
+                fees: round_to_dollars(fees),
+                gain: round_to_dollars(profit - fees),
+            });
+            position.pending_fees = 0.0;
+
+            lot.quantity -= closed_quantity;
+            lot.usd_value -= open_value;
+            lot.fee -= open_fee;
+            if lot.quantity <= DUST_QUANTITY {
+                position.lots.pop_front();
+            }
+            quantity -= closed_quantity;
+        }
+
+        if quantity > DUST_QUANTITY {
+            position.lots.push_back(PositionLot {
+                asset: transaction.asset.clone(),
+                side: side.to_string(),
+                transaction_id: transaction.id.clone(),
+                datetime: transaction.datetime,
+                quantity,
+                usd_value: transaction.usd_value * (quantity / transaction.quantity),
+                fee: fee * (quantity / transaction.quantity),
+            });
+        }
+    }
+
+    (gains, positions)
+}
+
+pub fn save_margin_gains_to_file(gains: &[MarginGain], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for gain in gains {
+        writer.serialize(gain)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn save_margin_positions_to_file(positions: &BTreeMap<String, MarginPosition>, out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for lot in positions.values().flat_map(|p| p.lots.iter()) {
+        writer.serialize(lot)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Deserialize;
+
+use account::{TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_HIFO, TAX_ACCOUNTING_METHOD_LIFO, TAX_ACCOUNTING_METHOD_SPECIFIC_ID};
+use period::TaxPeriod;
+
+/// A tax accounting method elected from `from` to `to`, both inclusive, for the sales of `asset` from `wallet`.
+/// An empty column applies to every date, asset or wallet.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MethodElection {
+    pub method: String,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub asset: Option<String>,
+    pub wallet: Option<String>,
+}
+
+/// The method elections of a policy, with the dates of the elections evaluated in the time zone of the schedule.
+#[derive(Debug, Clone)]
+pub struct MethodSchedule {
+    elections: Vec<(MethodElection, TaxPeriod)>,
+}
+
+impl MethodSchedule {
+    pub fn new(elections: Vec<MethodElection>, time_zone: &FixedOffset) -> MethodSchedule {
+        MethodSchedule {
+            elections: elections
+                .into_iter()
+                .map(|e| {
+                    let period = TaxPeriod::date_range(e.from, e.to, time_zone);
+                    (e, period)
+                })
+                .collect(),
+        }
+    }
+
+    /// The method of the most specific election covering a sale of `asset` from `wallet` at `datetime`. A wallet
+    /// election is more specific than an asset one; between elections as specific, the last row of the file wins.
+    pub fn method_for(&self, datetime: DateTime<Utc>, asset: &str, wallet: &str) -> Option<&str> {
+        let matches = |value: &Option<String>, actual: &str| value.as_ref().is_none_or(|v| v == actual);
+        self.elections
+            .iter()
+            .enumerate()
+            .filter(|(_, (e, period))| period.contains(datetime) && matches(&e.asset, asset) && matches(&e.wallet, wallet))
+            .max_by_key(|(i, (e, _))| (2 * e.wallet.is_some() as u8 + e.asset.is_some() as u8, *i))
+            .map(|(_, (e, _))| e.method.as_str())
+    }
+}
+
+pub fn read_method_schedule(file_path: &str, time_zone: &FixedOffset) -> Result<MethodSchedule, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut elections = vec![];
+    for row in reader.deserialize() {
+        let election: MethodElection = row?;
+        if ![TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_LIFO, TAX_ACCOUNTING_METHOD_HIFO, TAX_ACCOUNTING_METHOD_SPECIFIC_ID].contains(&election.method.as_str()) {
+            return Err(format!("unsupported tax accounting method in the method schedule: {}", election.method).into());
+        }
+        elections.push(election);
+    }
+    Ok(MethodSchedule::new(elections, time_zone))
+}
+
+/// The quantity of the lot created by `buy_transaction_id` that the sale `sell_transaction_id` disposes of.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LotDesignation {
+    pub sell_transaction_id: String,
+    pub buy_transaction_id: String,
+    pub quantity: f64,
+}
+
+/// Lot designations by sell transaction id, in the order of the file.
+pub fn read_lot_designations(file_path: &str) -> Result<HashMap<String, Vec<LotDesignation>>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut designations: HashMap<String, Vec<LotDesignation>> = HashMap::new();
+    for row in reader.deserialize() {
+        let designation: LotDesignation = row?;
+        designations.entry(designation.sell_transaction_id.clone()).or_default().push(designation);
+    }
+    Ok(designations)
+}
@@ -0,0 +1,76 @@
+extern crate chrono;
+
+use self::chrono::prelude::*;
+
+/// A reporting period `[start, end)`. Only disposals and income inside the period are reported,
+/// while the full history is still replayed to build the lots.
+#[derive(Debug, Clone)]
+pub struct TaxPeriod {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl TaxPeriod {
+    /// January 1st to December 31st of `year`, with the year boundaries in `time_zone`.
+    pub fn tax_year(year: i32, time_zone: &FixedOffset) -> TaxPeriod {
+        TaxPeriod {
+            start: start_of_day(NaiveDate::from_ymd_opt(year, 1, 1).expect("a valid tax year"), time_zone),
+            end: start_of_day(NaiveDate::from_ymd_opt(year + 1, 1, 1).expect("a valid tax year"), time_zone),
+        }
+    }
+
+    /// From the start of `from` to the end of `to`, both inclusive and in `time_zone`.
+    /// A missing bound leaves that side of the period open.
+    pub fn date_range(from: Option<NaiveDate>, to: Option<NaiveDate>, time_zone: &FixedOffset) -> TaxPeriod {
+        TaxPeriod {
+            start: from
+                .map(|date| start_of_day(date, time_zone))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+            end: to
+                .and_then(|date| date.succ_opt())
+                .map(|date| start_of_day(date, time_zone))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+
+    pub fn contains(&self, datetime: DateTime<Utc>) -> bool {
+        datetime >= self.start && datetime < self.end
+    }
+}
+
+/// The calendar year `datetime` falls in when seen from `time_zone`.
+pub fn tax_year_of(datetime: DateTime<Utc>, time_zone: &FixedOffset) -> i32 {
+    datetime.with_timezone(time_zone).year()
+}
+
+/// Parses `UTC` or a fixed offset such as `-05:00`, `+0100` or `+01`.
+pub fn parse_utc_offset(s: &str) -> Result<FixedOffset, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("UTC") || s == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let invalid = || format!("invalid UTC offset: {}", s);
+    let (sign, digits) = match s.chars().next() {
+        Some('+') => (1, &s[1..]),
+        Some('-') => (-1, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits = digits.replace(':', "");
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().map_err(|_| invalid())?, 0),
+        4 => (
+            digits[..2].parse::<i32>().map_err(|_| invalid())?,
+            digits[2..].parse::<i32>().map_err(|_| invalid())?,
+        ),
+        _ => return Err(invalid()),
+    };
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+fn start_of_day(date: NaiveDate, time_zone: &FixedOffset) -> DateTime<Utc> {
+    time_zone
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .unwrap()
+        .with_timezone(&Utc)
+}
@@ -0,0 +1,228 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Serialize;
+
+use account::{Deposit, Account, TAX_ACCOUNTING_METHOD_SPECIFIC_ID};
+use period::tax_year_of;
+use tax::{capital_gain_type, round_to_dollars, IncomeEvent, ProcessingOptions, TaxEvent, CAPITAL_GAIN_TYPE_LONG};
+use tax_estimate::TaxEstimator;
+use unrealized::DUST_QUANTITY;
+
+/// A lot, or part of a lot, to sell.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedSale {
+    pub asset: String,
+    pub buy_transaction_id: String,
+    pub buy_date: DateTime<Utc>,
+    pub quantity: f64,
+    pub price: f64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub gain: f64,
+    pub term: String,
+    /// Tax added by this sale to the year realized so far and the sales planned before it.
+    pub estimated_tax: f64,
+}
+
+#[derive(Debug)]
+pub struct CashPlan {
+    pub sales: Vec<PlannedSale>,
+    pub raised: f64,
+    /// What could not be raised with the lots held.
+    pub shortfall: f64,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    /// Tax added by the plan to the tax of the year realized so far.
+    pub estimated_tax: f64,
+}
+
+/// The income and the gains realized in a tax year, which the gains of a plan are added to.
+#[derive(Debug, Default, Clone)]
+pub struct RealizedYear {
+    pub year: i32,
+    pub income: f64,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+}
+
+impl RealizedYear {
+    /// Sums the tax events and the income of `year`, years evaluated in `time_zone`.
+    pub fn new(year: i32, tax_events: &[TaxEvent], income: &[IncomeEvent], time_zone: &FixedOffset) -> RealizedYear {
+        let mut realized = RealizedYear {
+            year,
+            ..Default::default()
+        };
+        for tax_event in tax_events.iter().filter(|e| tax_year_of(e.sell_date, time_zone) == year) {
+            if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+                realized.long_term_gain += tax_event.gain;
+            } else {
+                realized.short_term_gain += tax_event.gain;
+            }
+        }
+        realized.income = income.iter().filter(|i| tax_year_of(i.datetime, time_zone) == year).map(|i| i.usd_value).sum();
+        realized
+    }
+
+    /// The tax added by `short_term_gain` and `long_term_gain` on top of the realized gains and income.
+    fn tax_added(&self, estimator: &dyn TaxEstimator, short_term_gain: f64, long_term_gain: f64) -> f64 {
+        let tax_after = estimator.estimate_tax(self.year, self.income, self.short_term_gain + short_term_gain, self.long_term_gain + long_term_gain);
+        tax_after - estimator.estimate_tax(self.year, self.income, self.short_term_gain, self.long_term_gain)
+    }
+}
+
+/// A lot that can be sold.
+struct Candidate<'a> {
+    name: &'a String,
+    deposit: &'a Deposit,
+    price: f64,
+    term: &'static str,
+    proceeds: f64,
+    gain: f64,
+}
+
+/// Picks the lots to sell at `datetime`, in the tax year of `realized`, to raise `target` USD for the least tax.
+/// Every lot is ranked by the tax its sale adds to the year realized so far and the sales already picked, per
+/// dollar raised, estimated by `estimator`, then by its gain per dollar raised, so lots with a loss come first,
+/// then long term lots with a small gain and short term lots with a large gain last. The lots of an asset are
+/// sold in the order of the accounting method of `options` for the asset: only with specific identification is
+/// every lot picked by its rank. USD already held is not counted.
+pub fn plan_cash_raise(accounts: &HashMap<String, Account>, prices: &HashMap<String, f64>, datetime: DateTime<Utc>, target: f64, realized: &RealizedYear, options: &ProcessingOptions, estimator: &dyn TaxEstimator) -> CashPlan {
+    // The tax added by a gain to the gains planned so far.
+    let gain_tax = |gain: f64, term: &str, short_term_gain: f64, long_term_gain: f64| {
+        let tax_before = realized.tax_added(estimator, short_term_gain, long_term_gain);
+        if term == CAPITAL_GAIN_TYPE_LONG {
+            realized.tax_added(estimator, short_term_gain, long_term_gain + gain) - tax_before
+        } else {
+            realized.tax_added(estimator, short_term_gain + gain, long_term_gain) - tax_before
+        }
+    };
+    let rank = |c: &Candidate, short_term_gain: f64, long_term_gain: f64| (gain_tax(c.gain, c.term, short_term_gain, long_term_gain) / c.proceeds, c.gain / c.proceeds);
+
+    let mut names = accounts.keys().filter(|name| name.as_str() != "USD").collect::<Vec<&String>>();
+    names.sort();
+    let mut queues: Vec<VecDeque<Candidate>> = vec![];
+    for name in names {
+        let account = &accounts[name];
+        let price = match prices.get(name) {
+            Some(price) if *price > 0.0 => *price,
+            _ => {
+                if account.balance > DUST_QUANTITY {
+                    eprintln!("No price for {}, it is not sold", name);
+                }
+                continue;
+            }
+        };
+        // Wallet elections don't apply, the plan sells from all the lots of the asset.
+        let method = options.method_for(datetime, name, "");
+        let mut queue = account
+            .open_lots_in_order(if method == TAX_ACCOUNTING_METHOD_SPECIFIC_ID { &options.tax_accounting_method } else { method })
+            .into_iter()
+            .filter(|deposit| deposit.remaining_quantity > DUST_QUANTITY && deposit.datetime <= datetime)
+            .map(|deposit| {
+                let term = capital_gain_type(deposit.datetime, datetime);
+                let proceeds = deposit.remaining_quantity * price;
+                Candidate {
+                    name,
+                    deposit,
+                    price,
+                    term,
+                    proceeds,
+                    gain: proceeds - deposit.remaining_cost_basis(),
+                }
+            })
+            .collect::<Vec<Candidate>>();
+        if method == TAX_ACCOUNTING_METHOD_SPECIFIC_ID {
+            queue.sort_by(|c1, c2| {
+                let (r1, r2) = (rank(c1, 0.0, 0.0), rank(c2, 0.0, 0.0));
+                r1.0.total_cmp(&r2.0).then(r1.1.total_cmp(&r2.1)).then(c1.deposit.datetime.cmp(&c2.deposit.datetime))
+            });
+        }
+        queues.push(queue.into());
+    }
+
+    let mut sales = vec![];
+    let mut raised = 0.0;
+    let mut short_term_gain = 0.0;
+    let mut long_term_gain = 0.0;
+    while raised < target {
+        // The next lot of every asset is the only one that can be sold, the cheapest of them is.
+        let next = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, queue)| queue.front().map(|c| (i, rank(c, short_term_gain, long_term_gain))))
+            .min_by(|(i1, r1), (i2, r2)| r1.0.total_cmp(&r2.0).then(r1.1.total_cmp(&r2.1)).then(i1.cmp(i2)));
+        let Candidate { name, deposit, price, term, .. } = match next {
+            Some((i, _)) => queues[i].pop_front().unwrap(),
+            None => break,
+        };
+        let quantity = deposit.remaining_quantity.min((target - raised) / price);
+        let proceeds = quantity * price;
+        let cost_basis = deposit.usd_value * (quantity / deposit.quantity);
+        let estimated_tax = gain_tax(proceeds - cost_basis, term, short_term_gain, long_term_gain);
+        raised += proceeds;
+        if term == CAPITAL_GAIN_TYPE_LONG {
+            long_term_gain += proceeds - cost_basis;
+        } else {
+            short_term_gain += proceeds - cost_basis;
+        }
+        sales.push(PlannedSale {
+            asset: name.clone(),
+            buy_transaction_id: deposit.transaction_id.clone(),
+            buy_date: deposit.datetime,
+            quantity,
+            price,
+            proceeds: round_to_dollars(proceeds),
+            cost_basis: round_to_dollars(cost_basis),
+            gain: round_to_dollars(proceeds - cost_basis),
+            term: term.to_string(),
+            estimated_tax: round_to_dollars(estimated_tax),
+        });
+    }
+
+    CashPlan {
+        sales,
+        raised: round_to_dollars(raised),
+        shortfall: round_to_dollars((target - raised).max(0.0)),
+        short_term_gain: round_to_dollars(short_term_gain),
+        long_term_gain: round_to_dollars(long_term_gain),
+        estimated_tax: round_to_dollars(realized.tax_added(estimator, short_term_gain, long_term_gain)),
+    }
+}
+
+/// Prints the sell orders, one per asset, and the totals of the plan.
+pub fn print_cash_plan(plan: &CashPlan) {
+    let mut orders: BTreeMap<&str, (f64, f64, f64)> = BTreeMap::new();
+    for sale in plan.sales.iter() {
+        let order = orders.entry(&sale.asset).or_default();
+        order.0 += sale.quantity;
+        order.1 += sale.proceeds;
+        order.2 += sale.gain;
+    }
+
+    println!("{:<8} {:>16} {:>16} {:>16}", "Sell", "Quantity", "Proceeds", "Gain");
+    for (asset, (quantity, proceeds, gain)) in orders {
+        println!("{:<8} {:>16.8} {:>16.2} {:>16.2}", asset, quantity, proceeds, gain);
+    }
+    println!(
+        "Raised {:.2} with a short term gain of {:.2} and a long term gain of {:.2}, estimated tax {:.2}.",
+        plan.raised, plan.short_term_gain, plan.long_term_gain, plan.estimated_tax
+    );
+    if plan.shortfall > 0.0 {
+        println!("The lots held are {:.2} short of the target.", plan.shortfall);
+    }
+}
+
+pub fn save_cash_plan_to_file(plan: &CashPlan, out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for sale in plan.sales.iter() {
+        writer.serialize(sale)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+use self::chrono::prelude::*;
+use self::chrono::Duration;
+
+use serde::Deserialize;
+
+use tax::Transaction;
+
+pub const PRICE_INTERPOLATION_PREVIOUS: &str = "previous";
+pub const PRICE_INTERPOLATION_NEAREST: &str = "nearest";
+pub const PRICE_INTERPOLATION_LINEAR: &str = "linear";
+
+/// Relative difference between a recorded `usd_value` and the price database
+/// above which the recorded value is reported as suspicious.
+pub const USD_VALUE_TOLERANCE: f64 = 0.1;
+
+/// Prices further away than this from the requested timestamp are not used.
+const MAX_PRICE_DISTANCE_DAYS: i64 = 7;
+
+const ASSET_COLUMNS: [&str; 2] = ["asset", "symbol"];
+const DATETIME_COLUMNS: [&str; 5] = ["datetime", "date", "timestamp", "time", "snapped_at"];
+const PRICE_COLUMNS: [&str; 2] = ["price", "close"];
+
+/// Historical USD prices per asset, indexed by time.
+#[derive(Debug, Default)]
+pub struct PriceDatabase {
+    prices: HashMap<String, BTreeMap<DateTime<Utc>, f64>>,
+}
+
+impl PriceDatabase {
+    pub fn new() -> PriceDatabase {
+        PriceDatabase {
+            prices: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, asset: &str, datetime: DateTime<Utc>, price: f64) {
+        self.prices
+            .entry(normalize_asset(asset))
+            .or_default()
+            .insert(datetime, price);
+    }
+
+    /// Loads a daily or OHLC price export. The file must have a date column
+    /// (`datetime`, `date`, `timestamp`, `time` or `snapped_at`) and a `price` or `close` column.
+    /// When there is no `asset`/`symbol` column the asset is taken from the file name,
+    /// e.g. `btc-usd-max.csv` as exported by CoinGecko.
+    pub fn load_file(&mut self, file_path: &str) -> Result<usize, Box<dyn Error>> {
+        let file = File::open(file_path)?;
+        let mut reader = csv::Reader::from_reader(file);
+        let headers = reader.headers()?.clone();
+
+        let find_column = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+        };
+        let asset_column = find_column(&ASSET_COLUMNS);
+        let datetime_column = find_column(&DATETIME_COLUMNS)
+            .ok_or_else(|| format!("{}: no datetime column", file_path))?;
+        let price_column = find_column(&PRICE_COLUMNS)
+            .ok_or_else(|| format!("{}: no price or close column", file_path))?;
+        let file_asset = asset_from_file_name(file_path);
+
+        let mut loaded = 0;
+        for record in reader.records() {
+            let record = record?;
+            let asset = match asset_column {
+                Some(column) => record[column].to_string(),
+                None => file_asset
+                    .clone()
+                    .ok_or_else(|| format!("{}: no asset column", file_path))?,
+            };
+            let datetime = parse_price_datetime(&record[datetime_column])?;
+            let price: f64 = record[price_column].trim().parse()?;
+            self.insert(&asset, datetime, price);
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+
+    /// Returns the USD price of one unit of `asset` at `datetime`.
+    pub fn price_at(&self, asset: &str, datetime: DateTime<Utc>, interpolation: &str) -> Option<f64> {
+        let asset = normalize_asset(asset);
+        if asset == "USD" {
+            return Some(1.0);
+        }
+
+        let prices = self.prices.get(&asset)?;
+        let max_distance = Duration::days(MAX_PRICE_DISTANCE_DAYS);
+        let previous = prices
+            .range(..=datetime)
+            .next_back()
+            .filter(|(t, _)| datetime.signed_duration_since(**t) <= max_distance);
+        let next = prices
+            .range(datetime..)
+            .next()
+            .filter(|(t, _)| t.signed_duration_since(datetime) <= max_distance);
+
+        if interpolation == PRICE_INTERPOLATION_PREVIOUS {
+            previous.map(|(_, p)| *p)
+        } else if interpolation == PRICE_INTERPOLATION_NEAREST {
+            match (previous, next) {
+                (Some((t0, p0)), Some((t1, p1))) => {
+                    if datetime.signed_duration_since(*t0) <= t1.signed_duration_since(datetime) {
+                        Some(*p0)
+                    } else {
+                        Some(*p1)
+                    }
+                }
+                (Some((_, p)), None) | (None, Some((_, p))) => Some(*p),
+                (None, None) => None,
+            }
+        } else if interpolation == PRICE_INTERPOLATION_LINEAR {
+            match (previous, next) {
+                (Some((t0, p0)), Some((t1, p1))) => {
+                    let span = t1.signed_duration_since(*t0).num_seconds();
+                    if span == 0 {
+                        return Some(*p0);
+                    }
+                    let elapsed = datetime.signed_duration_since(*t0).num_seconds();
+                    Some(p0 + (p1 - p0) * (elapsed as f64 / span as f64))
+                }
+                (Some((_, p)), None) | (None, Some((_, p))) => Some(*p),
+                (None, None) => None,
+            }
+        } else {
+            panic!("Unsupported price interpolation:{}", interpolation);
+        }
+    }
+
+    /// Values `quantity` units of `asset` in USD at `datetime`.
+    pub fn usd_value(&self, asset: &str, quantity: f64, datetime: DateTime<Utc>, interpolation: &str) -> Option<f64> {
+        self.price_at(asset, datetime, interpolation)
+            .map(|price| price * quantity)
+    }
+
+    /// Market value of a transaction, taken from the USD side when there is one,
+    /// otherwise from the origin asset and then the destination asset.
+    pub fn transaction_usd_value(&self, transaction: &Transaction, interpolation: &str) -> Option<f64> {
+        if normalize_asset(&transaction.origin_asset) == "USD" {
+            return Some(transaction.origin_quantity);
+        }
+        if normalize_asset(&transaction.destination_asset) == "USD" {
+            return Some(transaction.destination_quantity);
+        }
+
+        self.usd_value(&transaction.origin_asset, transaction.origin_quantity, transaction.datetime, interpolation)
+            .or_else(|| self.usd_value(&transaction.destination_asset, transaction.destination_quantity, transaction.datetime, interpolation))
+    }
+
+    /// The prices of `assets` at `datetime`. Assets without a price are left out.
+    pub fn snapshot<'a, I>(&self, assets: I, datetime: DateTime<Utc>, interpolation: &str) -> HashMap<String, f64>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        assets
+            .into_iter()
+            .filter_map(|asset| {
+                self.price_at(asset, datetime, interpolation)
+                    .map(|price| (asset.clone(), price))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PriceSnapshotRecord {
+    asset: String,
+    price: f64,
+}
+
+/// Reads a price snapshot file with one `asset,price` row per asset.
+pub fn read_price_snapshot(file_path: &str) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut snapshot = HashMap::new();
+    for record in reader.deserialize() {
+        let record: PriceSnapshotRecord = record?;
+        snapshot.insert(record.asset.trim().to_uppercase(), record.price);
+    }
+    Ok(snapshot)
+}
+
+/// Fills the `usd_value` of transactions that don't have one.
+/// Returns the ids of the transactions that could not be valued.
+pub fn fill_missing_usd_values(transactions: &mut [Transaction], prices: &PriceDatabase, interpolation: &str) -> Vec<String> {
+    let mut unpriced = vec![];
+    for transaction in transactions.iter_mut() {
+        // A migration carries the cost basis of its lots over, its value is not used.
+        if transaction.usd_value.is_some() || transaction.is_migration() {
+            continue;
+        }
+        match prices.transaction_usd_value(transaction, interpolation) {
+            Some(usd_value) => transaction.usd_value = Some(usd_value),
+            None => unpriced.push(transaction.id.clone()),
+        }
+    }
+    unpriced
+}
+
+/// Compares the recorded `usd_value` of every transaction with the price database and
+/// returns a warning for each one that differs by more than `tolerance`.
+pub fn validate_usd_values(transactions: &[Transaction], prices: &PriceDatabase, interpolation: &str, tolerance: f64) -> Vec<String> {
+    let mut warnings = vec![];
+    for transaction in transactions {
+        let usd_value = match transaction.usd_value {
+            Some(usd_value) if !transaction.is_migration() => usd_value,
+            _ => continue,
+        };
+        if let Some(market_value) = prices.transaction_usd_value(transaction, interpolation) {
+            let difference = (usd_value - market_value).abs() / market_value.abs().max(0.01);
+            if difference > tolerance {
+                warnings.push(format!(
+                    "transaction {}: usd_value {} differs from the market value {:.2} by {:.0}%",
+                    transaction.id,
+                    usd_value,
+                    market_value,
+                    difference * 100.0
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+fn normalize_asset(asset: &str) -> String {
+    let asset = asset.trim().to_uppercase();
+    if asset == "XBT" {
+        "BTC".to_string()
+    } else {
+        asset
+    }
+}
+
+fn asset_from_file_name(file_path: &str) -> Option<String> {
+    Path::new(file_path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.split(['-', '_', '.']).next())
+        .filter(|asset| !asset.is_empty())
+        .map(normalize_asset)
+}
+
+/// Accepts RFC 3339, `2017-01-01 00:00:00 UTC`, plain dates and unix timestamps in seconds or milliseconds.
+pub fn parse_price_datetime(s: &str) -> Result<DateTime<Utc>, String> {
+    let s = s.trim();
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    let without_zone = s.trim_end_matches(" UTC");
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(without_zone, "%Y-%m-%d %H:%M:%S%.f") {
+        return Ok(Utc.from_utc_datetime(&datetime));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(without_zone, "%Y-%m-%d") {
+        return Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()));
+    }
+    if let Ok(timestamp) = s.parse::<i64>() {
+        let datetime = if timestamp > 100_000_000_000 {
+            Utc.timestamp_millis_opt(timestamp).single()
+        } else {
+            Utc.timestamp_opt(timestamp, 0).single()
+        };
+        if let Some(datetime) = datetime {
+            return Ok(datetime);
+        }
+    }
+    Err(format!("unrecognized price datetime: {}", s))
+}
@@ -0,0 +1,134 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use account::{Account, TAX_ACCOUNTING_METHOD_FIFO};
+use tax::{compare_transactions, Transaction};
+
+/// Balances that differ by less than this are considered equal.
+const QUANTITY_TOLERANCE: f64 = 1e-8;
+
+/// A balance reported by an exchange or wallet at a point in time.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BalanceStatement {
+    pub wallet: String,
+    pub asset: String,
+    pub datetime: DateTime<Utc>,
+    pub quantity: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReconciliationRow {
+    pub wallet: String,
+    pub asset: String,
+    pub datetime: DateTime<Utc>,
+    pub reported_quantity: f64,
+    pub computed_quantity: f64,
+    pub difference: f64,
+    /// Ids of the transactions of the wallet and asset since the last statement that matched,
+    /// empty when this statement matches.
+    pub transactions_to_review: String,
+}
+
+impl ReconciliationRow {
+    pub fn is_match(&self) -> bool {
+        self.difference.abs() < QUANTITY_TOLERANCE
+    }
+}
+
+pub fn read_balance_statements(file_path: &str) -> Result<Vec<BalanceStatement>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut statements = vec![];
+    for statement in reader.deserialize() {
+        statements.push(statement?);
+    }
+    Ok(statements)
+}
+
+/// Replays the quantities of every wallet and compares them with the statements at each statement date.
+/// The wallets start with the open lots of `opening_accounts` allocated to them; the lots that are not allocated
+/// to a wallet can't be placed and are left out with a warning, USD is always left out.
+/// A transaction moves `origin_quantity` out of its origin wallet and `destination_quantity` into its destination wallet.
+pub fn reconcile(transactions: &[Transaction], opening_accounts: &HashMap<String, Account>, statements: &[BalanceStatement]) -> Vec<ReconciliationRow> {
+    let mut transactions = transactions.iter().collect::<Vec<&Transaction>>();
+    transactions.sort_by(|t1, t2| compare_transactions(t1, t2));
+    let mut statements = statements.iter().collect::<Vec<&BalanceStatement>>();
+    statements.sort_by_key(|s| s.datetime);
+
+    let mut balances: HashMap<(String, String), f64> = HashMap::new();
+    for account in opening_accounts.values().filter(|a| a.name != "USD") {
+        let mut unallocated = 0.0;
+        for lot in account.open_lots_in_order(TAX_ACCOUNTING_METHOD_FIFO) {
+            match &lot.wallet {
+                Some(wallet) => *balances.entry((wallet.clone(), account.name.clone())).or_insert(0.0) += lot.remaining_quantity,
+                None => unallocated += lot.remaining_quantity,
+            }
+        }
+        if unallocated > QUANTITY_TOLERANCE {
+            eprintln!("{} {} of the opening lots are not allocated to a wallet and are left out of the reconciliation", unallocated, account.name);
+        }
+    }
+    let mut last_matches: HashMap<(String, String), DateTime<Utc>> = HashMap::new();
+    let mut rows = vec![];
+    let mut next_transaction = 0;
+
+    for statement in statements {
+        while next_transaction < transactions.len() && transactions[next_transaction].datetime <= statement.datetime {
+            let transaction = transactions[next_transaction];
+            *balances
+                .entry((transaction.origin_wallet.clone(), transaction.origin_asset.clone()))
+                .or_insert(0.0) -= transaction.origin_quantity;
+            *balances
+                .entry((transaction.destination_wallet.clone(), transaction.destination_asset.clone()))
+                .or_insert(0.0) += transaction.destination_quantity;
+            next_transaction += 1;
+        }
+
+        let key = (statement.wallet.clone(), statement.asset.clone());
+        let computed_quantity = balances.get(&key).cloned().unwrap_or(0.0);
+        let mut row = ReconciliationRow {
+            wallet: statement.wallet.clone(),
+            asset: statement.asset.clone(),
+            datetime: statement.datetime,
+            reported_quantity: statement.quantity,
+            computed_quantity,
+            difference: computed_quantity - statement.quantity,
+            transactions_to_review: String::new(),
+        };
+
+        if row.is_match() {
+            last_matches.insert(key, statement.datetime);
+        } else {
+            let since = last_matches.get(&key).cloned();
+            row.transactions_to_review = transactions[..next_transaction]
+                .iter()
+                .filter(|t| since.is_none_or(|since| t.datetime > since))
+                .filter(|t| {
+                    (t.origin_wallet == key.0 && t.origin_asset == key.1)
+                        || (t.destination_wallet == key.0 && t.destination_asset == key.1)
+                })
+                .map(|t| t.id.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ");
+        }
+        rows.push(row);
+    }
+
+    rows
+}
+
+pub fn save_reconciliation_to_file(rows: &[ReconciliationRow], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use period::tax_year_of;
+use tax::{round_to_dollars, TaxEvent, CAPITAL_GAIN_TYPE_LONG};
+use tax_estimate::capital_loss_limit;
+
+/// Capital losses left at the end of `year`, as positive amounts that keep their term.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct CapitalLossCarryforward {
+    pub year: i32,
+    pub short_term: f64,
+    pub long_term: f64,
+}
+
+/// The carryforward into `first_year`: the row of the latest earlier year of the file.
+pub fn read_capital_loss_carryforward(file_path: &str, first_year: i32) -> Result<Option<CapitalLossCarryforward>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut carryforward: Option<CapitalLossCarryforward> = None;
+    for row in reader.deserialize() {
+        let row: CapitalLossCarryforward = row?;
+        if row.year < first_year && carryforward.as_ref().is_none_or(|c| c.year < row.year) {
+            carryforward = Some(row);
+        }
+    }
+    Ok(carryforward)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleDYear {
+    pub year: i32,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub short_term_carryover: f64,
+    pub long_term_carryover: f64,
+    /// Gains of the year less the losses carried over, before netting the two terms.
+    pub net_short_term: f64,
+    pub net_long_term: f64,
+    pub net_gain: f64,
+    /// The part of a net loss deducted from ordinary income.
+    pub loss_deduction: f64,
+    pub short_term_carryforward: f64,
+    pub long_term_carryforward: f64,
+}
+
+impl ScheduleDYear {
+    pub fn carryforward(&self) -> CapitalLossCarryforward {
+        CapitalLossCarryforward {
+            year: self.year,
+            short_term: self.short_term_carryforward,
+            long_term: self.long_term_carryforward,
+        }
+    }
+}
+
+/// Nets the gains and losses of a year the way Schedule D does: the losses carried over reduce the gains of
+/// their own term, a loss of one term then offsets a gain of the other and a net loss is deducted from ordinary
+/// income up to the yearly limit. What is left is carried forward, short term losses being deducted first.
+pub fn net_capital_gains(year: i32, short_term_gain: f64, long_term_gain: f64, carryover: &CapitalLossCarryforward, filing_status: &str) -> ScheduleDYear {
+    let net_short_term = short_term_gain - carryover.short_term;
+    let net_long_term = long_term_gain - carryover.long_term;
+    let net_gain = net_short_term + net_long_term;
+
+    let mut loss_deduction = 0.0;
+    let mut short_term_carryforward = 0.0;
+    let mut long_term_carryforward = 0.0;
+    if net_gain < 0.0 {
+        loss_deduction = (-net_gain).min(capital_loss_limit(filing_status));
+        if net_long_term >= 0.0 {
+            short_term_carryforward = -net_gain - loss_deduction;
+        } else if net_short_term >= 0.0 {
+            long_term_carryforward = -net_gain - loss_deduction;
+        } else {
+            short_term_carryforward = (-net_short_term - loss_deduction).max(0.0);
+            long_term_carryforward = -net_long_term - (loss_deduction + net_short_term).max(0.0);
+        }
+    }
+
+    ScheduleDYear {
+        year,
+        short_term_gain: round_to_dollars(short_term_gain),
+        long_term_gain: round_to_dollars(long_term_gain),
+        short_term_carryover: round_to_dollars(carryover.short_term),
+        long_term_carryover: round_to_dollars(carryover.long_term),
+        net_short_term: round_to_dollars(net_short_term),
+        net_long_term: round_to_dollars(net_long_term),
+        net_gain: round_to_dollars(net_gain),
+        loss_deduction: round_to_dollars(loss_deduction),
+        short_term_carryforward: round_to_dollars(short_term_carryforward),
+        long_term_carryforward: round_to_dollars(long_term_carryforward),
+    }
+}
+
+/// Sums the tax events of every year, years evaluated in `time_zone`, and nets them from the first year with
+/// tax events to the last one, carrying the losses from one year to the next.
+pub fn schedule_d_by_year(tax_events: &[TaxEvent], opening_carryforward: Option<&CapitalLossCarryforward>, filing_status: &str, time_zone: &FixedOffset) -> Vec<ScheduleDYear> {
+    let mut gains: BTreeMap<i32, (f64, f64)> = BTreeMap::new();
+    for tax_event in tax_events {
+        let year = gains.entry(tax_year_of(tax_event.sell_date, time_zone)).or_default();
+        if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+            year.1 += tax_event.gain;
+        } else {
+            year.0 += tax_event.gain;
+        }
+    }
+    let (first_year, last_year) = match (gains.keys().next(), gains.keys().next_back()) {
+        (Some(first_year), Some(last_year)) => (*first_year, *last_year),
+        _ => return vec![],
+    };
+
+    let mut carryover = opening_carryforward.cloned().unwrap_or_default();
+    let mut years = vec![];
+    for year in first_year..=last_year {
+        let (short_term_gain, long_term_gain) = gains.get(&year).cloned().unwrap_or_default();
+        let netted = net_capital_gains(year, short_term_gain, long_term_gain, &carryover, filing_status);
+        carryover = netted.carryforward();
+        years.push(netted);
+    }
+    years
+}
+
+pub fn save_schedule_d_to_file(years: &[ScheduleDYear], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for year in years {
+        writer.serialize(year)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn save_capital_loss_carryforward_to_file(carryforward: &CapitalLossCarryforward, out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    writer.serialize(carryforward)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,119 @@
+extern crate chrono;
+
+use std::collections::{BTreeMap, HashMap};
+
+use self::chrono::prelude::*;
+
+use account::Account;
+use period::tax_year_of;
+use tax::{process_transaction, round_to_dollars, sort_transactions, IncomeEvent, ProcessingOptions, TaxEvent, Transaction, CAPITAL_GAIN_TYPE_LONG, WALLET_EXTERNAL, WALLET_INCOME, WALLET_NA};
+use tax_estimate::TaxEstimator;
+
+/// The tax events of hypothetical transactions, applied after the real history.
+pub struct Simulation {
+    pub tax_events: Vec<TaxEvent>,
+    /// Hypothetical transactions that sell more than the lots held.
+    pub short_transactions: Vec<String>,
+    /// Hypothetical transactions dated before the last real one, which are left out.
+    pub back_dated_transactions: Vec<String>,
+}
+
+/// Applies the hypothetical transactions to a copy of `accounts`, which are left as they are. `accounts` are the
+/// accounts at `history_end`, the datetime of the last real transaction, so the hypothetical transactions dated
+/// before it are left out: they would use lots acquired after them.
+pub fn simulate_transactions(accounts: &HashMap<String, Account>, history_end: Option<DateTime<Utc>>, hypothetical_transactions: &mut [Transaction], options: &ProcessingOptions) -> Simulation {
+    sort_transactions(hypothetical_transactions);
+
+    let mut accounts = accounts.clone();
+    let mut simulation = Simulation {
+        tax_events: vec![],
+        short_transactions: vec![],
+        back_dated_transactions: vec![],
+    };
+    for transaction in hypothetical_transactions.iter() {
+        if history_end.is_some_and(|history_end| transaction.datetime < history_end) {
+            simulation.back_dated_transactions.push(transaction.id.clone());
+            continue;
+        }
+        let held = accounts.get(&transaction.origin_asset).map(|a| a.balance).unwrap_or(0.0);
+        let spends_lots = transaction.origin_wallet != WALLET_EXTERNAL && transaction.origin_wallet != WALLET_NA && transaction.origin_wallet != WALLET_INCOME;
+        if spends_lots && transaction.origin_asset != "USD" && transaction.origin_quantity > held {
+            simulation.short_transactions.push(transaction.id.clone());
+        }
+        simulation.tax_events.extend(process_transaction(transaction, &mut accounts, options));
+    }
+    simulation
+}
+
+/// The gains of the simulated tax events of a year and the tax they add to the real gains and income of that year.
+#[derive(Debug)]
+pub struct SimulatedTaxImpact {
+    pub year: i32,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub tax: f64,
+}
+
+pub fn simulated_tax_impact(real_tax_events: &[TaxEvent], income: &[IncomeEvent], simulated_tax_events: &[TaxEvent], estimator: &dyn TaxEstimator, time_zone: &FixedOffset) -> Vec<SimulatedTaxImpact> {
+    let sum_by_year = |tax_events: &[TaxEvent]| {
+        let mut years: BTreeMap<i32, (f64, f64)> = BTreeMap::new();
+        for tax_event in tax_events {
+            let year = years.entry(tax_year_of(tax_event.sell_date, time_zone)).or_default();
+            if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+                year.1 += tax_event.gain;
+            } else {
+                year.0 += tax_event.gain;
+            }
+        }
+        years
+    };
+    let real_gains = sum_by_year(real_tax_events);
+
+    sum_by_year(simulated_tax_events)
+        .into_iter()
+        .map(|(year, (short_term_gain, long_term_gain))| {
+            let (real_short_term_gain, real_long_term_gain) = real_gains.get(&year).cloned().unwrap_or_default();
+            let real_income: f64 = income.iter().filter(|i| tax_year_of(i.datetime, time_zone) == year).map(|i| i.usd_value).sum();
+            let tax_before = estimator.estimate_tax(year, real_income, real_short_term_gain, real_long_term_gain);
+            let tax_after = estimator.estimate_tax(year, real_income, real_short_term_gain + short_term_gain, real_long_term_gain + long_term_gain);
+            SimulatedTaxImpact {
+                year,
+                short_term_gain: round_to_dollars(short_term_gain),
+                long_term_gain: round_to_dollars(long_term_gain),
+                tax: round_to_dollars(tax_after - tax_before),
+            }
+        })
+        .collect()
+}
+
+pub fn print_simulation(simulation: &Simulation, impact: &[SimulatedTaxImpact]) {
+    for id in simulation.back_dated_transactions.iter() {
+        println!("Transaction {} is dated before the last real transaction and is left out.", id);
+    }
+    for id in simulation.short_transactions.iter() {
+        println!("Transaction {} sells more than the lots held, only the lots held are used.", id);
+    }
+    println!(
+        "{:<12} {:<8} {:>16} {:<12} {:>14} {:>14} {:>14} {:<6}",
+        "Transaction", "Asset", "Quantity", "Buy date", "Cost basis", "Proceeds", "Gain", "Term"
+    );
+    for tax_event in simulation.tax_events.iter() {
+        println!(
+            "{:<12} {:<8} {:>16.8} {:<12} {:>14.2} {:>14.2} {:>14.2} {:<6}",
+            tax_event.sell_transaction_id,
+            tax_event.asset,
+            tax_event.quantity,
+            tax_event.buy_date.format("%Y-%m-%d").to_string(),
+            tax_event.cost_basis,
+            tax_event.proceeds,
+            tax_event.gain,
+            tax_event.term
+        );
+    }
+    for year in impact {
+        println!(
+            "{}: short term {:.2}, long term {:.2}, additional tax {:.2}",
+            year.year, year.short_term_gain, year.long_term_gain, year.tax
+        );
+    }
+}
@@ -0,0 +1,244 @@
+extern crate csv;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use account::Account;
+use period::TaxPeriod;
+use tax::{calculate_income, compare_transactions, process_transaction, same_timestamp_conflicts, sort_transactions, ProcessingOptions, Transaction, CAPITAL_GAIN_TYPE_LONG, WALLET_INCOME};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 100_000;
+
+pub type TransactionResult = Result<Transaction, Box<dyn Error>>;
+
+/// Reads a transactions file one transaction at a time. The file must already be sorted.
+pub fn read_sorted_transactions(file_path: &str) -> Result<impl Iterator<Item = TransactionResult>, Box<dyn Error>> {
+    let reader = csv::Reader::from_path(file_path)?;
+    Ok(reader
+        .into_deserialize::<Transaction>()
+        .map(|t| t.map_err(|e| e.into())))
+}
+
+/// Sorts a transactions file of any size: chunks of `chunk_size` transactions are sorted in memory and
+/// written to temporary files, which are then merged while they are read.
+pub fn read_transactions_with_external_sort(file_path: &str, chunk_size: usize) -> Result<MergedTransactions, Box<dyn Error>> {
+    let chunks_dir = create_chunks_dir()?;
+
+    let mut chunk_files = vec![];
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut write_chunk = |chunk: &mut Vec<Transaction>| -> Result<(), Box<dyn Error>> {
+        sort_transactions(chunk);
+        let chunk_file = chunks_dir.join(format!("chunk-{}.csv", chunk_files.len()));
+        let mut writer = csv::Writer::from_path(&chunk_file)?;
+        for transaction in chunk.drain(..) {
+            writer.serialize(transaction)?;
+        }
+        writer.flush()?;
+        chunk_files.push(chunk_file);
+        Ok(())
+    };
+
+    let mut reader = csv::Reader::from_path(file_path)?;
+    for transaction in reader.deserialize() {
+        chunk.push(transaction?);
+        if chunk.len() >= chunk_size {
+            write_chunk(&mut chunk)?;
+        }
+    }
+    if !chunk.is_empty() {
+        write_chunk(&mut chunk)?;
+    }
+
+    MergedTransactions::new(chunks_dir, chunk_files)
+}
+
+/// Creates a new directory for the chunk files of a sort. The name is unique to the process, the sort and the time,
+/// and an existing directory, e.g. left over by a crashed run, is never reused.
+fn create_chunks_dir() -> Result<PathBuf, Box<dyn Error>> {
+    static SORTS: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let sort = SORTS.fetch_add(1, AtomicOrdering::Relaxed);
+        let chunks_dir = std::env::temp_dir().join(format!("pine-tree-tax-{}-{}-{}", process::id(), sort, nanos));
+        match fs::create_dir(&chunks_dir) {
+            Ok(()) => return Ok(chunks_dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+struct MergeEntry {
+    transaction: Transaction,
+    chunk: usize,
+}
+
+impl PartialEq for MergeEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeEntry {}
+
+impl Ord for MergeEntry {
+    // Reversed, so that the heap returns the first transaction.
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_transactions(&other.transaction, &self.transaction).then(other.chunk.cmp(&self.chunk))
+    }
+}
+
+impl PartialOrd for MergeEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Merges sorted chunk files, holding a single transaction per chunk in memory.
+/// The chunk files are deleted when it is dropped.
+pub struct MergedTransactions {
+    chunks_dir: PathBuf,
+    chunks: Vec<csv::DeserializeRecordsIntoIter<File, Transaction>>,
+    heap: BinaryHeap<MergeEntry>,
+}
+
+impl MergedTransactions {
+    fn new(chunks_dir: PathBuf, chunk_files: Vec<PathBuf>) -> Result<MergedTransactions, Box<dyn Error>> {
+        let mut merged = MergedTransactions {
+            chunks_dir,
+            chunks: vec![],
+            heap: BinaryHeap::new(),
+        };
+        for chunk_file in chunk_files {
+            merged.chunks.push(csv::Reader::from_path(chunk_file)?.into_deserialize());
+            let chunk = merged.chunks.len() - 1;
+            merged.read_next(chunk)?;
+        }
+        Ok(merged)
+    }
+
+    fn read_next(&mut self, chunk: usize) -> Result<(), Box<dyn Error>> {
+        if let Some(transaction) = self.chunks[chunk].next() {
+            self.heap.push(MergeEntry {
+                transaction: transaction?,
+                chunk,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedTransactions {
+    type Item = TransactionResult;
+
+    fn next(&mut self) -> Option<TransactionResult> {
+        let entry = self.heap.pop()?;
+        if let Err(e) = self.read_next(entry.chunk) {
+            return Some(Err(e));
+        }
+        Some(Ok(entry.transaction))
+    }
+}
+
+impl Drop for MergedTransactions {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.chunks_dir);
+    }
+}
+
+/// Output files of a streaming run. Only the tax events and income inside `period` are written; the income file
+/// is only created when there is income.
+pub struct StreamOutput<'a> {
+    pub long_gains_file: &'a Path,
+    pub short_gains_file: &'a Path,
+    pub income_file: &'a Path,
+    pub period: Option<TaxPeriod>,
+}
+
+#[derive(Debug, Default)]
+pub struct StreamSummary {
+    pub transactions: usize,
+    pub tax_events: usize,
+    pub income_events: usize,
+}
+
+/// Processes sorted transactions one at a time, writing each tax event and income event as soon as it is produced.
+/// Every `prune_every` transactions the exhausted lots are dropped from the accounts, so memory is bounded by
+/// the number of open lots. Fails on the first transaction that is out of order. Like the in memory processing,
+/// prints a warning for every conflict between transactions sharing a datetime.
+pub fn process_transactions_stream<I>(transactions: I, mut accounts: HashMap<String, Account>, options: &ProcessingOptions, output: &StreamOutput, prune_every: usize) -> Result<(HashMap<String, Account>, StreamSummary), Box<dyn Error>>
+where
+    I: Iterator<Item = TransactionResult>,
+{
+    let mut long_gains = csv::Writer::from_path(output.long_gains_file)?;
+    let mut short_gains = csv::Writer::from_path(output.short_gains_file)?;
+    let mut income: Option<csv::Writer<File>> = None;
+    let mut summary = StreamSummary::default();
+    let mut previous: Option<Transaction> = None;
+    // The transactions sharing the datetime of the last one, checked for conflicts once the datetime changes.
+    let mut same_time: Vec<Transaction> = vec![];
+
+    for transaction in transactions {
+        let transaction = transaction?;
+        if let Some(previous) = &previous {
+            if compare_transactions(previous, &transaction) == Ordering::Greater {
+                return Err(format!("transaction {} is out of order, it comes before transaction {}", transaction.id, previous.id).into());
+            }
+        }
+        if same_time.first().is_some_and(|t| t.datetime != transaction.datetime) {
+            for conflict in same_timestamp_conflicts(&same_time) {
+                eprintln!("{}", conflict);
+            }
+            same_time.clear();
+        }
+        same_time.push(transaction.clone());
+
+        let in_period = output.period.as_ref().is_none_or(|period| period.contains(transaction.datetime));
+        for tax_event in process_transaction(&transaction, &mut accounts, options) {
+            if !in_period {
+                continue;
+            }
+            if tax_event.term == CAPITAL_GAIN_TYPE_LONG {
+                long_gains.serialize(&tax_event)?;
+            } else {
+                short_gains.serialize(&tax_event)?;
+            }
+            summary.tax_events += 1;
+        }
+        if in_period && transaction.origin_wallet == WALLET_INCOME {
+            for income_event in calculate_income(std::slice::from_ref(&transaction)) {
+                if income.is_none() {
+                    income = Some(csv::Writer::from_path(output.income_file)?);
+                }
+                income.as_mut().unwrap().serialize(income_event)?;
+                summary.income_events += 1;
+            }
+        }
+
+        summary.transactions += 1;
+        if prune_every > 0 && summary.transactions % prune_every == 0 {
+            for account in accounts.values_mut() {
+                account.prune_exhausted_lots();
+            }
+        }
+        previous = Some(transaction);
+    }
+
+    for conflict in same_timestamp_conflicts(&same_time) {
+        eprintln!("{}", conflict);
+    }
+
+    long_gains.flush()?;
+    short_gains.flush()?;
+    if let Some(income) = income.as_mut() {
+        income.flush()?;
+    }
+    Ok((accounts, summary))
+}
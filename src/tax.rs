@@ -1,7 +1,8 @@
 extern crate chrono;
 extern crate csv;
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use self::chrono::prelude::*;
 use self::chrono::Duration;
 use std::error::Error;
@@ -9,16 +10,28 @@ use std::fs::File;
 
 use serde::{Deserialize, Serialize};
 
+use period::tax_year_of;
 use account::Account;
 use account::Deposit;
+use account::{TAX_ACCOUNTING_METHOD_FIFO, TAX_ACCOUNTING_METHOD_SPECIFIC_ID};
+use equivalence::EquivalenceRules;
+use method_schedule::{LotDesignation, MethodSchedule};
+use unrealized::DUST_QUANTITY;
+use wallet_allocation::WalletAllocation;
 
-const WALLET_EXTERNAL: &str = "External";
-const WALLET_NA: &str = "N/A";
+pub const WALLET_EXTERNAL: &str = "External";
+pub const WALLET_NA: &str = "N/A";
+/// Origin wallet of mining, staking, airdrop and other rewards received as ordinary income.
+pub const WALLET_INCOME: &str = "Income";
+
+/// Converts every open lot of the origin asset into the destination asset at the ratio of the transaction's
+/// quantities, e.g. a token migration, a redenomination or a split.
+pub const TRANSACTION_TYPE_MIGRATION: &str = "migration";
 
 pub const CAPITAL_GAIN_TYPE_LONG: &str = "long";
 pub const CAPITAL_GAIN_TYPE_SHORT: &str = "short";
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Transaction {
     pub id: String,
     pub datetime: DateTime<Utc>,
@@ -28,11 +41,32 @@ pub struct Transaction {
     pub destination_wallet: String,
     pub destination_asset: String,
     pub destination_quantity: f64,
-    pub usd_value: f64,
+    /// Empty when the value is unknown, to be filled from the price database.
+    pub usd_value: Option<f64>,
     pub usd_fee: Option<f64>,
+    /// The asset the fee was paid in, USD when empty. Optional column.
+    #[serde(default)]
+    pub fee_asset: Option<String>,
+    /// Orders transactions sharing the same datetime. Optional column.
+    #[serde(default)]
+    pub sequence: Option<u64>,
+    /// Empty for a trade or a transfer, `migration` for a migration. Optional column.
+    #[serde(rename = "type", default)]
+    pub type_: Option<String>,
+}
+
+impl Transaction {
+    /// The `usd_value`, 0.0 when it is unknown and could not be filled.
+    pub fn usd_value_or_zero(&self) -> f64 {
+        self.usd_value.unwrap_or(0.0)
+    }
+
+    pub fn is_migration(&self) -> bool {
+        self.type_.as_deref() == Some(TRANSACTION_TYPE_MIGRATION)
+    }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TaxEvent {
     pub quantity: f64,
     pub asset: String,
@@ -41,12 +75,162 @@ pub struct TaxEvent {
     pub cost_basis: f64,
     pub proceeds: f64,
     pub gain: f64,
+    /// `long` or `short`.
+    pub term: String,
+    /// The part of the transaction's `usd_fee` allocated to the sold lot by quantity.
+    pub fee: f64,
+    /// Id of the transaction that sold the lot.
+    pub sell_transaction_id: String,
+    /// Id of the transaction that created the sold lot.
+    pub buy_transaction_id: String,
+    pub origin_wallet: String,
+    /// The asset received in exchange, USD for a sale.
+    pub received_asset: String,
+    /// The tax accounting method that selected the lot.
+    pub method: String,
+}
+
+/// Long term when the asset was held for at least a year, short term otherwise.
+pub fn capital_gain_type(buy_date: DateTime<Utc>, sell_date: DateTime<Utc>) -> &'static str {
+    if sell_date.signed_duration_since(buy_date) >= Duration::days(365) {
+        CAPITAL_GAIN_TYPE_LONG
+    } else {
+        CAPITAL_GAIN_TYPE_SHORT
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomeEvent {
+    pub id: String,
+    pub datetime: DateTime<Utc>,
+    pub wallet: String,
+    pub asset: String,
+    pub quantity: f64,
+    pub usd_value: f64,
+}
+
+/// Every transaction coming from the `Income` wallet is ordinary income at its USD value,
+/// which is also the cost basis of the received lot.
+pub fn calculate_income(transactions: &[Transaction]) -> Vec<IncomeEvent> {
+    transactions
+        .iter()
+        .filter(|t| t.origin_wallet == WALLET_INCOME)
+        .map(|t| IncomeEvent {
+            id: t.id.clone(),
+            datetime: t.datetime,
+            wallet: t.destination_wallet.clone(),
+            asset: t.destination_asset.clone(),
+            quantity: t.destination_quantity,
+            usd_value: round_to_dollars(t.usd_value_or_zero()),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessingOptions {
+    pub tax_accounting_method: String,
+    /// Allows a withdrawal to use the deposits made at the same datetime, e.g. a buy and a sell that
+    /// an exchange export records in the same second. By default only earlier deposits can be used.
+    pub same_time_deposits_spendable: bool,
+    /// Elections that override `tax_accounting_method` for some dates, assets or wallets.
+    pub method_schedule: Option<MethodSchedule>,
+    /// The lots sold by each sale made with the specific identification method, by sell transaction id.
+    pub lot_designations: HashMap<String, Vec<LotDesignation>>,
+    /// Tracks the lots per wallet from the cutoff of the allocation on, instead of pooling them by asset.
+    pub wallet_allocation: Option<WalletAllocation>,
+    /// Asset pairs whose conversions carry the lots over instead of selling them.
+    pub equivalence_rules: Option<EquivalenceRules>,
+}
+
+impl ProcessingOptions {
+    pub fn new(tax_accounting_method: &str) -> ProcessingOptions {
+        ProcessingOptions {
+            tax_accounting_method: tax_accounting_method.to_string(),
+            same_time_deposits_spendable: false,
+            method_schedule: None,
+            lot_designations: HashMap::new(),
+            wallet_allocation: None,
+            equivalence_rules: None,
+        }
+    }
+
+    /// The method that applies to a sale of `asset` from `wallet` at `datetime`.
+    pub fn method_for(&self, datetime: DateTime<Utc>, asset: &str, wallet: &str) -> &str {
+        self.method_schedule
+            .as_ref()
+            .and_then(|schedule| schedule.method_for(datetime, asset, wallet))
+            .unwrap_or(&self.tax_accounting_method)
+    }
+
+    /// The method used for what the designated lots of a specific identification sale don't cover.
+    fn fallback_method(&self) -> &str {
+        if self.tax_accounting_method == TAX_ACCOUNTING_METHOD_SPECIFIC_ID {
+            TAX_ACCOUNTING_METHOD_FIFO
+        } else {
+            &self.tax_accounting_method
+        }
+    }
+}
+
+/// Transactions are processed by datetime, then by sequence and then by id.
+/// Ids that are numbers are compared as numbers.
+pub fn compare_transactions(t1: &Transaction, t2: &Transaction) -> Ordering {
+    t1.datetime
+        .cmp(&t2.datetime)
+        .then(t1.sequence.cmp(&t2.sequence))
+        .then_with(|| match (t1.id.parse::<u64>(), t2.id.parse::<u64>()) {
+            (Ok(id1), Ok(id2)) => id1.cmp(&id2),
+            _ => t1.id.cmp(&t2.id),
+        })
+}
+
+pub fn sort_transactions(transactions: &mut [Transaction]) {
+    transactions.sort_by(compare_transactions);
 }
 
-pub fn calculate_capital_gains(mut transactions: &mut Vec<Transaction>, tax_accounting_method: &str) -> (Vec<TaxEvent>, HashMap<String,Account>) {
-    transactions.sort_by(|t1, t2| t1.datetime.cmp(&t2.datetime));
+/// Finds transactions sharing a datetime where one spends an asset that another one receives,
+/// whose result depends on their order and on `same_time_deposits_spendable`.
+pub fn same_timestamp_conflicts(transactions: &[Transaction]) -> Vec<String> {
+    let mut by_datetime: BTreeMap<DateTime<Utc>, Vec<&Transaction>> = BTreeMap::new();
+    for transaction in transactions {
+        by_datetime.entry(transaction.datetime).or_default().push(transaction);
+    }
+
+    let mut conflicts = vec![];
+    for (datetime, same_time) in by_datetime.iter().filter(|(_, t)| t.len() > 1) {
+        for spending in same_time.iter().filter(|t| spends_lots(t)) {
+            for receiving in same_time.iter().filter(|t| t.id != spending.id && t.destination_asset == spending.origin_asset) {
+                conflicts.push(format!(
+                    "transactions {} and {} at {} both use {}: {} is spent while {} deposits it",
+                    spending.id, receiving.id, datetime, spending.origin_asset, spending.id, receiving.id
+                ));
+            }
+        }
+    }
+    conflicts
+}
 
-    let mut accounts = hashmap! {
+fn spends_lots(transaction: &Transaction) -> bool {
+    transaction.origin_wallet != WALLET_NA
+        && transaction.origin_wallet != WALLET_INCOME
+        && transaction.origin_asset != "USD"
+        && !is_transfer_between_own_wallets(transaction)
+}
+
+/// A conversion between assets that the equivalence rules make non-taxable: it carries its lots over.
+pub fn is_non_taxable_conversion(transaction: &Transaction, options: &ProcessingOptions) -> bool {
+    spends_lots(transaction) && options.equivalence_rules.as_ref().is_some_and(|rules| rules.is_non_taxable(&transaction.origin_asset, &transaction.destination_asset))
+}
+
+pub fn is_transfer_between_own_wallets(transaction: &Transaction) -> bool {
+    transaction.origin_asset == transaction.destination_asset
+        && transaction.origin_wallet != WALLET_EXTERNAL
+        && transaction.destination_wallet != WALLET_EXTERNAL
+        && transaction.origin_wallet != WALLET_INCOME
+}
+
+pub fn default_accounts() -> HashMap<String, Account> {
+    hashmap! {
         "USD".to_string() => Account::new("USD".to_string(), 100000000.0),
         "BTC".to_string() => Account::new("BTC".to_string(), 0.0),
         "ETH".to_string() => Account::new("ETH".to_string(), 0.0),
@@ -59,55 +243,272 @@ pub fn calculate_capital_gains(mut transactions: &mut Vec<Transaction>, tax_acco
         "ADA".to_string() =>  Account::new("ADA".to_string(), 0.0),
         "BITB".to_string() =>  Account::new("BITB".to_string(), 0.0),
         "XZC".to_string() =>  Account::new("XZC".to_string(), 0.0),
-    };
+    }
+}
+
+#[cfg(test)]
+pub fn calculate_capital_gains(transactions: &mut [Transaction], tax_accounting_method: &str) -> (Vec<TaxEvent>, HashMap<String,Account>) {
+    calculate_capital_gains_with_accounts(transactions, default_accounts(), &ProcessingOptions::new(tax_accounting_method))
+}
+
+/// Same as `calculate_capital_gains` but starts from existing accounts, e.g. the remaining lots of the previous year.
+pub fn calculate_capital_gains_with_accounts(transactions: &mut [Transaction], mut accounts: HashMap<String, Account>, options: &ProcessingOptions) -> (Vec<TaxEvent>, HashMap<String,Account>) {
+    sort_transactions(transactions);
 
     let mut tax_events: Vec<TaxEvent> = vec![];
 
     for transaction in transactions.iter() {
-        if transaction.origin_asset == transaction.destination_asset
-            && transaction.origin_wallet != WALLET_EXTERNAL
-            && transaction.destination_wallet != WALLET_EXTERNAL
-        {
-            continue;
+        tax_events.extend(process_transaction(transaction, &mut accounts, options));
+    }
+
+    (tax_events, accounts)
+}
+
+/// Replays all the transactions once and also returns the accounts as they were at the end of each tax year,
+/// from the year of the first transaction to the year of the last one.
+pub fn calculate_capital_gains_by_year(transactions: &mut [Transaction], mut accounts: HashMap<String, Account>, options: &ProcessingOptions, time_zone: &FixedOffset) -> (Vec<TaxEvent>, BTreeMap<i32, HashMap<String, Account>>) {
+    sort_transactions(transactions);
+
+    let mut tax_events: Vec<TaxEvent> = vec![];
+    let mut year_end_accounts = BTreeMap::new();
+    let mut current_year = None;
+
+    for transaction in transactions.iter() {
+        let year = tax_year_of(transaction.datetime, time_zone);
+        if let Some(previous_year) = current_year {
+            for closed_year in previous_year..year {
+                year_end_accounts.insert(closed_year, accounts.clone());
+            }
         }
-        let mut deposits: Vec<Deposit> = vec![];
-        if transaction.origin_wallet != WALLET_NA {
-            if let Some(account) = accounts.get_mut(&transaction.origin_asset) {
-                deposits = account.withdraw(transaction.datetime, transaction.origin_quantity, tax_accounting_method)
+        current_year = Some(year);
+        tax_events.extend(process_transaction(transaction, &mut accounts, options));
+    }
+
+    if let Some(year) = current_year {
+        year_end_accounts.insert(year, accounts);
+    }
+
+    (tax_events, year_end_accounts)
+}
+
+/// The cost basis a migration or a non-taxable conversion moves from the origin asset to the destination asset.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CarriedCostBasis {
+    /// The cost basis of the origin lots carried over.
+    pub carried: f64,
+    /// The cost basis of the lot acquired for the quantity the origin lots didn't cover.
+    pub uncovered: f64,
+}
+
+/// Applies a single transaction to the accounts and returns the tax events it triggers.
+pub fn process_transaction(transaction: &Transaction, accounts: &mut HashMap<String, Account>, options: &ProcessingOptions) -> Vec<TaxEvent> {
+    process_transaction_carrying_lots(transaction, accounts, options).0
+}
+
+/// Like `process_transaction`, and also returns the cost basis the transaction carried over without a sale.
+pub fn process_transaction_carrying_lots(transaction: &Transaction, accounts: &mut HashMap<String, Account>, options: &ProcessingOptions) -> (Vec<TaxEvent>, CarriedCostBasis) {
+    let mut tax_events: Vec<TaxEvent> = vec![];
+
+    // USD is always pooled.
+    let per_wallet = |asset: &str| options.wallet_allocation.as_ref().filter(|a| a.applies_to(transaction.datetime) && asset != "USD");
+
+    if transaction.is_migration() {
+        let mut lots = vec![];
+        if let Some(account) = accounts.get_mut(&transaction.origin_asset) {
+            if let Some(allocation) = per_wallet(&transaction.origin_asset) {
+                allocation.allocate(account);
             }
+            lots = account.withdraw(transaction.datetime, f64::INFINITY, TAX_ACCOUNTING_METHOD_FIFO, true);
+        }
+        if lots.is_empty() {
+            eprintln!("Migration {}: no {} lots held, nothing is migrated", transaction.id, transaction.origin_asset);
         }
 
-        accounts
-            .get_mut(&transaction.destination_asset)
-            .unwrap()
-            .deposit(
-                transaction.datetime,
-                transaction.destination_quantity,
-                transaction.usd_value,
-            );
+        let account = accounts
+            .entry(transaction.destination_asset.clone())
+            .or_insert_with(|| Account::new(transaction.destination_asset.clone(), 0.0));
+        if let Some(allocation) = per_wallet(&transaction.destination_asset) {
+            allocation.allocate(account);
+        }
+        // Every lot keeps its date, its total cost basis and its wallet.
+        let carried_cost_basis = CarriedCostBasis {
+            carried: lots.iter().map(|lot| lot.usd_value).sum(),
+            uncovered: 0.0,
+        };
+        let ratio = transaction.destination_quantity / transaction.origin_quantity;
+        for mut lot in lots {
+            lot.quantity *= ratio;
+            lot.remaining_quantity = lot.quantity;
+            account.add_lot(lot);
+        }
+        return (tax_events, carried_cost_basis);
+    }
 
-        if transaction.origin_asset != "USD" && deposits.len() > 0 {
-            for deposit in deposits.iter() {
-                let proceeds = round_to_dollars(
-                    transaction.usd_value * (deposit.quantity / transaction.origin_quantity),
-                );
-                let cost_basis = round_to_dollars(deposit.usd_value);
-                tax_events.push(TaxEvent {
-                    quantity: deposit.quantity,
-                    asset: transaction.origin_asset.clone(),
-                    buy_date: deposit.datetime,
-                    sell_date: transaction.datetime,
-                    cost_basis,
-                    proceeds,
-                    gain: round_to_dollars(proceeds - cost_basis),
+    if is_transfer_between_own_wallets(transaction) {
+        if let (Some(allocation), Some(account)) = (per_wallet(&transaction.origin_asset), accounts.get_mut(&transaction.origin_asset)) {
+            allocation.allocate(account);
+            let wallet = Some(transaction.origin_wallet.as_str());
+            for mut lot in withdraw_carried_lots(transaction, transaction.destination_quantity, account, options, wallet) {
+                lot.wallet = Some(transaction.destination_wallet.clone());
+                account.add_lot(lot);
+            }
+            // The fee paid in the asset leaves the origin wallet with its lots.
+            let fee_quantity = transaction.origin_quantity - transaction.destination_quantity;
+            if fee_quantity > 0.0 {
+                withdraw_carried_lots(transaction, fee_quantity, account, options, wallet);
+            }
+        }
+        return (tax_events, CarriedCostBasis::default());
+    }
+
+    if is_non_taxable_conversion(transaction, options) {
+        let mut lots = vec![];
+        if let Some(account) = accounts.get_mut(&transaction.origin_asset) {
+            let wallet = per_wallet(&transaction.origin_asset).map(|allocation| {
+                allocation.allocate(account);
+                transaction.origin_wallet.as_str()
+            });
+            lots = withdraw_carried_lots(transaction, transaction.origin_quantity, account, options, wallet);
+        }
+
+        let account = accounts
+            .entry(transaction.destination_asset.clone())
+            .or_insert_with(|| Account::new(transaction.destination_asset.clone(), 0.0));
+        let wallet = per_wallet(&transaction.destination_asset).map(|allocation| {
+            allocation.allocate(account);
+            transaction.destination_wallet.clone()
+        });
+        let ratio = transaction.destination_quantity / transaction.origin_quantity;
+        let carried_quantity: f64 = lots.iter().map(|lot| lot.quantity).sum();
+        let mut carried_cost_basis = CarriedCostBasis {
+            carried: lots.iter().map(|lot| lot.usd_value).sum(),
+            uncovered: 0.0,
+        };
+        for mut lot in lots {
+            lot.quantity *= ratio;
+            lot.remaining_quantity = lot.quantity;
+            lot.wallet = wallet.clone();
+            account.add_lot(lot);
+        }
+        // What the lots held don't cover is acquired at the value of the transaction.
+        let uncovered_quantity = transaction.origin_quantity - carried_quantity;
+        if uncovered_quantity > DUST_QUANTITY {
+            let mut deposit = Deposit::new(&transaction.id, transaction.datetime, uncovered_quantity * ratio, transaction.usd_value_or_zero() * (uncovered_quantity / transaction.origin_quantity));
+            deposit.wallet = wallet;
+            carried_cost_basis.uncovered = deposit.usd_value;
+            account.add_lot(deposit);
+        }
+        return (tax_events, carried_cost_basis);
+    }
+    let mut deposits: Vec<Deposit> = vec![];
+    // The method that selected each of the deposits.
+    let mut methods: Vec<&str> = vec![];
+    if transaction.origin_wallet != WALLET_NA && transaction.origin_wallet != WALLET_INCOME {
+        if let Some(account) = accounts.get_mut(&transaction.origin_asset) {
+            let wallet = per_wallet(&transaction.origin_asset).map(|allocation| {
+                allocation.allocate(account);
+                transaction.origin_wallet.as_str()
+            });
+            let mut method = options.method_for(transaction.datetime, &transaction.origin_asset, &transaction.origin_wallet);
+            if method == TAX_ACCOUNTING_METHOD_SPECIFIC_ID {
+                let designations = options
+                    .lot_designations
+                    .get(&transaction.id)
+                    .map(|d| d.iter().map(|d| (d.buy_transaction_id.as_str(), d.quantity)).collect::<Vec<(&str, f64)>>())
+                    .unwrap_or_default();
+                if designations.is_empty() {
+                    eprintln!("No lots designated for transaction {}, {} is used", transaction.id, options.fallback_method());
+                }
+                let unhonored;
+                (deposits, unhonored) = account.withdraw_designated(transaction.datetime, transaction.origin_quantity, &designations, wallet, options.same_time_deposits_spendable);
+                methods = vec![TAX_ACCOUNTING_METHOD_SPECIFIC_ID; deposits.len()];
+                method = options.fallback_method();
+                for designation in unhonored {
+                    eprintln!(
+                        "Lot {} designated for transaction {} is {}, {} of it is sold with {}",
+                        designation.buy_transaction_id, transaction.id, designation.reason, designation.quantity, method
+                    );
+                }
+            }
+            let designated_quantity: f64 = deposits.iter().map(|d| d.quantity).sum();
+            if transaction.origin_quantity - designated_quantity > 0.0 {
+                let quantity = transaction.origin_quantity - designated_quantity;
+                deposits.extend(match wallet {
+                    Some(wallet) => account.withdraw_from_wallet(transaction.datetime, quantity, method, wallet, options.same_time_deposits_spendable),
+                    None => account.withdraw(transaction.datetime, quantity, method, options.same_time_deposits_spendable),
                 });
+                methods.resize(deposits.len(), method);
             }
         }
     }
 
-    (tax_events, accounts)
+    let mut parents: Vec<String> = vec![];
+    if transaction.origin_asset != "USD" {
+        for deposit in deposits.iter() {
+            if !parents.contains(&deposit.transaction_id) {
+                parents.push(deposit.transaction_id.clone());
+            }
+        }
+    }
+
+    let account = accounts
+        .entry(transaction.destination_asset.clone())
+        .or_insert_with(|| Account::new(transaction.destination_asset.clone(), 0.0));
+    let mut deposit = Deposit::new(&transaction.id, transaction.datetime, transaction.destination_quantity, transaction.usd_value_or_zero());
+    if !parents.is_empty() {
+        deposit.parent_asset = Some(transaction.origin_asset.clone());
+    }
+    deposit.parents = parents;
+    if let Some(allocation) = per_wallet(&transaction.destination_asset) {
+        allocation.allocate(account);
+        deposit.wallet = Some(transaction.destination_wallet.clone());
+    }
+    account.add_lot(deposit);
+
+    if transaction.origin_asset != "USD" && !deposits.is_empty() {
+        for (deposit, method) in deposits.iter().zip(methods) {
+            let proceeds = round_to_dollars(
+                transaction.usd_value_or_zero() * (deposit.quantity / transaction.origin_quantity),
+            );
+            let cost_basis = round_to_dollars(deposit.usd_value);
+            tax_events.push(TaxEvent {
+                quantity: deposit.quantity,
+                asset: transaction.origin_asset.clone(),
+                buy_date: deposit.datetime,
+                sell_date: transaction.datetime,
+                cost_basis,
+                proceeds,
+                gain: round_to_dollars(proceeds - cost_basis),
+                term: capital_gain_type(deposit.datetime, transaction.datetime).to_string(),
+                fee: round_to_dollars(
+                    transaction.usd_fee.unwrap_or_default() * (deposit.quantity / transaction.origin_quantity),
+                ),
+                sell_transaction_id: transaction.id.clone(),
+                buy_transaction_id: deposit.transaction_id.clone(),
+                origin_wallet: transaction.origin_wallet.clone(),
+                received_asset: transaction.destination_asset.clone(),
+                method: method.to_string(),
+            });
+        }
+    }
+
+    (tax_events, CarriedCostBasis::default())
+}
+
+/// Withdraws `quantity` of the lots a transfer or a non-taxable conversion carries over unchanged, from `wallet`
+/// when the lots are tracked per wallet. They are selected with the method of the transaction's date, asset and
+/// origin wallet.
+fn withdraw_carried_lots(transaction: &Transaction, quantity: f64, account: &mut Account, options: &ProcessingOptions, wallet: Option<&str>) -> Vec<Deposit> {
+    let mut method = options.method_for(transaction.datetime, &transaction.origin_asset, &transaction.origin_wallet);
+    if method == TAX_ACCOUNTING_METHOD_SPECIFIC_ID {
+        method = options.fallback_method();
+    }
+    match wallet {
+        Some(wallet) => account.withdraw_from_wallet(transaction.datetime, quantity, method, wallet, options.same_time_deposits_spendable),
+        None => account.withdraw(transaction.datetime, quantity, method, options.same_time_deposits_spendable),
+    }
 }
 
-fn round_to_dollars(num: f64) -> f64 {
+pub fn round_to_dollars(num: f64) -> f64 {
     return (100.0 * num).round() / 100.0;
 }
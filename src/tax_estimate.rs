@@ -0,0 +1,259 @@
+extern crate chrono;
+extern crate csv;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use period::tax_year_of;
+use schedule_d::{schedule_d_by_year, CapitalLossCarryforward};
+use tax::{round_to_dollars, IncomeEvent, TaxEvent};
+
+pub const FILING_STATUS_SINGLE: &str = "single";
+pub const FILING_STATUS_MARRIED_FILING_JOINTLY: &str = "married_filing_jointly";
+pub const FILING_STATUS_MARRIED_FILING_SEPARATELY: &str = "married_filing_separately";
+pub const FILING_STATUS_HEAD_OF_HOUSEHOLD: &str = "head_of_household";
+
+pub const BRACKET_KIND_ORDINARY: &str = "ordinary";
+pub const BRACKET_KIND_LONG_TERM: &str = "long_term";
+pub const BRACKET_KIND_NIIT: &str = "niit";
+pub const BRACKET_KIND_STATE: &str = "state";
+
+/// A net capital loss reduces ordinary income by at most this much a year.
+pub const CAPITAL_LOSS_LIMIT: f64 = 3000.0;
+pub const CAPITAL_LOSS_LIMIT_MARRIED_FILING_SEPARATELY: f64 = 1500.0;
+
+pub fn capital_loss_limit(filing_status: &str) -> f64 {
+    if filing_status == FILING_STATUS_MARRIED_FILING_SEPARATELY {
+        CAPITAL_LOSS_LIMIT_MARRIED_FILING_SEPARATELY
+    } else {
+        CAPITAL_LOSS_LIMIT
+    }
+}
+
+/// Estimates the tax caused by the crypto income and the capital gains of a year.
+pub trait TaxEstimator {
+    fn estimate_tax(&self, year: i32, crypto_income: f64, short_term_gain: f64, long_term_gain: f64) -> f64;
+}
+
+/// A row of the bracket table: `rate` applies to the income above `threshold`, up to the threshold of the next bracket.
+/// For `niit` the threshold is the modified adjusted gross income above which the net investment income tax applies.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaxBracket {
+    pub year: i32,
+    pub filing_status: String,
+    pub kind: String,
+    pub threshold: f64,
+    pub rate: f64,
+}
+
+/// `(threshold, rate)` brackets by year.
+type BracketsByYear = BTreeMap<i32, Vec<(f64, f64)>>;
+
+/// Bracket tables by filing status and kind.
+#[derive(Debug, Default)]
+pub struct TaxBrackets {
+    brackets: HashMap<(String, String), BracketsByYear>,
+    /// The years outside the table already warned about, by filing status.
+    warned_years: RefCell<BTreeSet<(String, i32)>>,
+}
+
+impl TaxBrackets {
+    pub fn insert(&mut self, bracket: TaxBracket) {
+        let brackets = self
+            .brackets
+            .entry((bracket.filing_status, bracket.kind))
+            .or_default()
+            .entry(bracket.year)
+            .or_default();
+        brackets.push((bracket.threshold, bracket.rate));
+        brackets.sort_by(|b1, b2| b1.0.total_cmp(&b2.0));
+    }
+
+    pub fn has_filing_status(&self, filing_status: &str) -> bool {
+        self.brackets.keys().any(|(status, _)| status == filing_status)
+    }
+
+    /// The `(threshold, rate)` brackets of a year, sorted by threshold. A year missing from the table uses the
+    /// closest earlier year, a year before the table its first year; a warning is printed once for a year after
+    /// the last one or before the first one. Empty when the kind is missing for the filing status.
+    pub fn brackets(&self, year: i32, filing_status: &str, kind: &str) -> &[(f64, f64)] {
+        let years = match self.brackets.get(&(filing_status.to_string(), kind.to_string())) {
+            Some(years) => years,
+            None => return &[],
+        };
+        let (first_year, last_year) = (*years.keys().next().unwrap(), *years.keys().next_back().unwrap());
+        if (year < first_year || year > last_year) && self.warned_years.borrow_mut().insert((filing_status.to_string(), year)) {
+            let used_year = if year < first_year { first_year } else { last_year };
+            eprintln!("No {} tax brackets for {}, those of {} are used", filing_status, year, used_year);
+        }
+        match years.range(..=year).next_back() {
+            Some((_, brackets)) => brackets.as_slice(),
+            None => years.values().next().unwrap().as_slice(),
+        }
+    }
+}
+
+pub fn read_tax_brackets(file_path: &str) -> Result<TaxBrackets, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut brackets = TaxBrackets::default();
+    for bracket in reader.deserialize() {
+        brackets.insert(bracket?);
+    }
+    Ok(brackets)
+}
+
+/// Tax on the part of the income between `from` and `to`, taxed by progressive brackets.
+fn progressive_tax(brackets: &[(f64, f64)], from: f64, to: f64) -> f64 {
+    let mut tax = 0.0;
+    for (i, (threshold, rate)) in brackets.iter().enumerate() {
+        let next_threshold = brackets.get(i + 1).map(|b| b.0).unwrap_or(f64::INFINITY);
+        let taxed = to.min(next_threshold) - from.max(*threshold);
+        if taxed > 0.0 {
+            tax += taxed * rate;
+        }
+    }
+    tax
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaxLiability {
+    pub ordinary_tax: f64,
+    pub long_term_tax: f64,
+    pub niit: f64,
+    pub state_tax: f64,
+}
+
+impl TaxLiability {
+    pub fn total(&self) -> f64 {
+        self.ordinary_tax + self.long_term_tax + self.niit + self.state_tax
+    }
+}
+
+/// The filing situation used to estimate the tax: the bracket table, the filing status, the taxable
+/// ordinary income from other sources (wages etc. after deductions) and the deductions taken from it,
+/// the same every year.
+pub struct TaxProfile {
+    pub brackets: TaxBrackets,
+    pub filing_status: String,
+    pub other_income: f64,
+    /// Added back to the taxable income to get the modified adjusted gross income used by the net investment income tax.
+    pub deductions: f64,
+}
+
+impl TaxProfile {
+    /// Net short term gains are taxed as ordinary income and net long term gains at the long term rates,
+    /// stacked on top of the ordinary income. Losses of one term offset gains of the other and a net loss
+    /// reduces ordinary income up to the yearly limit. The net investment income tax applies to the
+    /// capital gains by which the modified adjusted gross income, i.e. the taxable income before the deductions,
+    /// exceeds its threshold and the state taxes the whole income.
+    pub fn tax_liability(&self, year: i32, ordinary_income: f64, short_term_gain: f64, long_term_gain: f64) -> TaxLiability {
+        let bracket = |kind| self.brackets.brackets(year, &self.filing_status, kind);
+
+        let net_gain = short_term_gain + long_term_gain;
+        let (ordinary_gain, preferential_gain) = if net_gain < 0.0 {
+            (net_gain.max(-capital_loss_limit(&self.filing_status)), 0.0)
+        } else if long_term_gain < 0.0 {
+            (net_gain, 0.0)
+        } else if short_term_gain < 0.0 {
+            (0.0, net_gain)
+        } else {
+            (short_term_gain, long_term_gain)
+        };
+        let taxable_ordinary = (ordinary_income + ordinary_gain).max(0.0);
+        let taxable_income = taxable_ordinary + preferential_gain;
+        let modified_adjusted_gross_income = ordinary_income + ordinary_gain + preferential_gain + self.deductions;
+
+        let niit = bracket(BRACKET_KIND_NIIT)
+            .iter()
+            .map(|(threshold, rate)| (modified_adjusted_gross_income - threshold).max(0.0).min(net_gain.max(0.0)) * rate)
+            .sum();
+
+        TaxLiability {
+            ordinary_tax: progressive_tax(bracket(BRACKET_KIND_ORDINARY), 0.0, taxable_ordinary),
+            long_term_tax: progressive_tax(bracket(BRACKET_KIND_LONG_TERM), taxable_ordinary, taxable_income),
+            niit,
+            state_tax: progressive_tax(bracket(BRACKET_KIND_STATE), 0.0, taxable_income),
+        }
+    }
+
+    /// The tax added to the tax on `other_income` by the crypto income and gains of a year.
+    pub fn incremental_tax(&self, year: i32, crypto_income: f64, short_term_gain: f64, long_term_gain: f64) -> TaxLiability {
+        let with_crypto = self.tax_liability(year, self.other_income + crypto_income, short_term_gain, long_term_gain);
+        let without_crypto = self.tax_liability(year, self.other_income, 0.0, 0.0);
+        TaxLiability {
+            ordinary_tax: with_crypto.ordinary_tax - without_crypto.ordinary_tax,
+            long_term_tax: with_crypto.long_term_tax - without_crypto.long_term_tax,
+            niit: with_crypto.niit - without_crypto.niit,
+            state_tax: with_crypto.state_tax - without_crypto.state_tax,
+        }
+    }
+}
+
+impl TaxEstimator for TaxProfile {
+    fn estimate_tax(&self, year: i32, crypto_income: f64, short_term_gain: f64, long_term_gain: f64) -> f64 {
+        round_to_dollars(self.incremental_tax(year, crypto_income, short_term_gain, long_term_gain).total())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaxEstimate {
+    pub year: i32,
+    pub filing_status: String,
+    pub other_income: f64,
+    pub crypto_income: f64,
+    /// Gains less the losses carried over from earlier years.
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub ordinary_tax: f64,
+    pub long_term_tax: f64,
+    pub niit: f64,
+    pub state_tax: f64,
+    pub total_tax: f64,
+}
+
+/// The incremental tax of every year with tax events or income, years evaluated in `time_zone`.
+/// The capital losses carried over from earlier years, starting with `opening_carryforward`, reduce the gains.
+pub fn estimate_tax_by_year(tax_events: &[TaxEvent], income: &[IncomeEvent], opening_carryforward: Option<&CapitalLossCarryforward>, profile: &TaxProfile, time_zone: &FixedOffset) -> Vec<TaxEstimate> {
+    let mut years: BTreeMap<i32, (f64, f64, f64)> = BTreeMap::new();
+    for netted in schedule_d_by_year(tax_events, opening_carryforward, &profile.filing_status, time_zone) {
+        years.insert(netted.year, (0.0, netted.net_short_term, netted.net_long_term));
+    }
+    for income_event in income {
+        years.entry(tax_year_of(income_event.datetime, time_zone)).or_default().0 += income_event.usd_value;
+    }
+
+    years
+        .into_iter()
+        .map(|(year, (crypto_income, short_term_gain, long_term_gain))| {
+            let tax = profile.incremental_tax(year, crypto_income, short_term_gain, long_term_gain);
+            TaxEstimate {
+                year,
+                filing_status: profile.filing_status.clone(),
+                other_income: profile.other_income,
+                crypto_income: round_to_dollars(crypto_income),
+                short_term_gain: round_to_dollars(short_term_gain),
+                long_term_gain: round_to_dollars(long_term_gain),
+                ordinary_tax: round_to_dollars(tax.ordinary_tax),
+                long_term_tax: round_to_dollars(tax.long_term_tax),
+                niit: round_to_dollars(tax.niit),
+                state_tax: round_to_dollars(tax.state_tax),
+                total_tax: round_to_dollars(tax.total()),
+            }
+        })
+        .collect()
+}
+
+pub fn save_tax_estimates_to_file(estimates: &[TaxEstimate], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for estimate in estimates {
+        writer.serialize(estimate)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::Serialize;
+
+use account::Account;
+use tax::{capital_gain_type, round_to_dollars, CAPITAL_GAIN_TYPE_LONG};
+
+/// Quantities below this are rounding leftovers of exhausted lots.
+pub const DUST_QUANTITY: f64 = 1e-10;
+
+#[derive(Debug, Serialize)]
+pub struct UnrealizedLot {
+    pub asset: String,
+    pub buy_date: DateTime<Utc>,
+    pub quantity: f64,
+    pub cost_basis: f64,
+    pub price: f64,
+    pub market_value: f64,
+    pub gain: f64,
+    pub term: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UnrealizedAsset {
+    pub asset: String,
+    pub quantity: f64,
+    pub cost_basis: f64,
+    pub market_value: f64,
+    pub short_term_gain: f64,
+    pub long_term_gain: f64,
+    pub gain: f64,
+}
+
+/// Values the remaining lots held at `datetime` with the given prices. The term is what the
+/// gain would be if the lot was sold at `datetime`. Assets without a price are skipped.
+pub fn calculate_unrealized_gains(accounts: &HashMap<String, Account>, prices: &HashMap<String, f64>, datetime: DateTime<Utc>) -> (Vec<UnrealizedLot>, Vec<UnrealizedAsset>) {
+    let mut lots = vec![];
+    let mut assets: BTreeMap<String, UnrealizedAsset> = BTreeMap::new();
+
+    for (name, account) in accounts {
+        if name == "USD" {
+            continue;
+        }
+        let price = match prices.get(name) {
+            Some(price) => *price,
+            None => {
+                if account.balance > DUST_QUANTITY {
+                    eprintln!("No price for {}, its unrealized gains are not reported", name);
+                }
+                continue;
+            }
+        };
+
+        for deposit in account.deposits.iter() {
+            if deposit.remaining_quantity <= DUST_QUANTITY || deposit.datetime > datetime {
+                continue;
+            }
+            let cost_basis = round_to_dollars(deposit.usd_value * (deposit.remaining_quantity / deposit.quantity));
+            let market_value = round_to_dollars(deposit.remaining_quantity * price);
+            let gain = round_to_dollars(market_value - cost_basis);
+            let term = capital_gain_type(deposit.datetime, datetime);
+
+            let asset = assets.entry(name.clone()).or_insert_with(|| UnrealizedAsset {
+                asset: name.clone(),
+                ..Default::default()
+            });
+            asset.quantity += deposit.remaining_quantity;
+            asset.cost_basis = round_to_dollars(asset.cost_basis + cost_basis);
+            asset.market_value = round_to_dollars(asset.market_value + market_value);
+            if term == CAPITAL_GAIN_TYPE_LONG {
+                asset.long_term_gain = round_to_dollars(asset.long_term_gain + gain);
+            } else {
+                asset.short_term_gain = round_to_dollars(asset.short_term_gain + gain);
+            }
+            asset.gain = round_to_dollars(asset.gain + gain);
+
+            lots.push(UnrealizedLot {
+                asset: name.clone(),
+                buy_date: deposit.datetime,
+                quantity: deposit.remaining_quantity,
+                cost_basis,
+                price,
+                market_value,
+                gain,
+                term: term.to_string(),
+            });
+        }
+    }
+
+    lots.sort_by(|a, b| a.asset.cmp(&b.asset).then(a.buy_date.cmp(&b.buy_date)));
+    (lots, assets.into_values().collect())
+}
+
+pub fn save_unrealized_gains_to_file(lots: &[UnrealizedLot], assets: &[UnrealizedAsset], lots_file: &str, assets_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(lots_file)?);
+    for lot in lots {
+        writer.serialize(lot)?;
+    }
+    writer.flush()?;
+
+    let mut writer = csv::Writer::from_writer(File::create(assets_file)?);
+    for asset in assets {
+        writer.serialize(asset)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
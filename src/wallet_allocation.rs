@@ -0,0 +1,152 @@
+extern crate chrono;
+extern crate csv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+
+use self::chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use account::Account;
+use tax::{round_to_dollars, Transaction, WALLET_EXTERNAL, WALLET_INCOME, WALLET_NA};
+use unrealized::DUST_QUANTITY;
+
+/// `quantity` of the lot created by `buy_transaction_id` assigned to `wallet` when the lots start being
+/// tracked per wallet.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LotAllocation {
+    pub asset: String,
+    pub wallet: String,
+    pub buy_transaction_id: String,
+    pub buy_date: DateTime<Utc>,
+    pub quantity: f64,
+    pub cost_basis: f64,
+}
+
+/// Switches from lots pooled by asset to lots tracked per wallet at `cutoff`.
+#[derive(Debug, Clone)]
+pub struct WalletAllocation {
+    pub cutoff: DateTime<Utc>,
+    lots: HashMap<String, Vec<LotAllocation>>,
+}
+
+impl WalletAllocation {
+    pub fn new(cutoff: DateTime<Utc>, allocations: Vec<LotAllocation>) -> WalletAllocation {
+        let mut lots: HashMap<String, Vec<LotAllocation>> = HashMap::new();
+        for allocation in allocations {
+            lots.entry(allocation.asset.clone()).or_default().push(allocation);
+        }
+        WalletAllocation { cutoff, lots }
+    }
+
+    pub fn applies_to(&self, datetime: DateTime<Utc>) -> bool {
+        datetime >= self.cutoff
+    }
+
+    /// Allocates the lots of `account` to their wallets, the first time the account is used after the cutoff.
+    /// The lots are then as they were at the cutoff.
+    pub fn allocate(&self, account: &mut Account) {
+        if account.wallets_allocated {
+            return;
+        }
+        let allocations = self
+            .lots
+            .get(&account.name)
+            .map(|lots| lots.iter().map(|l| (l.buy_transaction_id.as_str(), l.wallet.as_str(), l.quantity)).collect::<Vec<(&str, &str, f64)>>())
+            .unwrap_or_default();
+        account.allocate_to_wallets(&allocations);
+    }
+}
+
+/// The quantity of every asset held in every wallet of the owner after `transactions`, by asset and wallet.
+/// USD and the `External`, `N/A` and `Income` wallets are left out.
+pub fn wallet_balances(transactions: &[Transaction]) -> BTreeMap<(String, String), f64> {
+    let is_own_wallet = |wallet: &str| wallet != WALLET_EXTERNAL && wallet != WALLET_NA && wallet != WALLET_INCOME;
+    let mut balances: BTreeMap<(String, String), f64> = BTreeMap::new();
+    for transaction in transactions {
+        if is_own_wallet(&transaction.origin_wallet) && transaction.origin_asset != "USD" {
+            *balances
+                .entry((transaction.origin_asset.clone(), transaction.origin_wallet.clone()))
+                .or_insert(0.0) -= transaction.origin_quantity;
+        }
+        if is_own_wallet(&transaction.destination_wallet) && transaction.destination_asset != "USD" {
+            *balances
+                .entry((transaction.destination_asset.clone(), transaction.destination_wallet.clone()))
+                .or_insert(0.0) += transaction.destination_quantity;
+        }
+    }
+    balances
+}
+
+/// Proposes an allocation of the open lots that matches the quantity held in every wallet, as the safe harbor
+/// for the move from pooled to per wallet tracking requires. For every asset, the lots are taken in the order of
+/// `tax_accounting_method` and fill the wallets in the order of their names. Also returns the differences
+/// between the lots and the wallet balances, which are left unallocated.
+pub fn propose_lot_allocation(accounts: &HashMap<String, Account>, balances: &BTreeMap<(String, String), f64>, tax_accounting_method: &str) -> (Vec<LotAllocation>, Vec<String>) {
+    let mut allocations = vec![];
+    let mut warnings = vec![];
+
+    let mut names = accounts.keys().filter(|name| name.as_str() != "USD").collect::<Vec<&String>>();
+    names.sort();
+    for name in names {
+        let mut wallets = balances
+            .iter()
+            .filter(|((asset, _), quantity)| asset == name && **quantity > DUST_QUANTITY)
+            .map(|((_, wallet), quantity)| (wallet, *quantity))
+            .collect::<Vec<(&String, f64)>>();
+        let mut next_wallet = 0;
+        let mut unallocated = 0.0;
+        for deposit in accounts[name].open_lots_in_order(tax_accounting_method) {
+            let mut left = deposit.remaining_quantity;
+            while left > DUST_QUANTITY && next_wallet < wallets.len() {
+                let (wallet, wallet_left) = &mut wallets[next_wallet];
+                let quantity = left.min(*wallet_left);
+                allocations.push(LotAllocation {
+                    asset: name.clone(),
+                    wallet: wallet.to_string(),
+                    buy_transaction_id: deposit.transaction_id.clone(),
+                    buy_date: deposit.datetime,
+                    quantity,
+                    cost_basis: round_to_dollars(deposit.usd_value * (quantity / deposit.quantity)),
+                });
+                left -= quantity;
+                *wallet_left -= quantity;
+                if *wallet_left <= DUST_QUANTITY {
+                    next_wallet += 1;
+                }
+            }
+            if left > DUST_QUANTITY {
+                unallocated += left;
+            }
+        }
+
+        if unallocated > 0.0 {
+            warnings.push(format!("{} {} of lots are not held in any wallet and stay unallocated", unallocated, name));
+        }
+        for (wallet, wallet_left) in wallets.iter().filter(|(_, left)| *left > DUST_QUANTITY) {
+            warnings.push(format!("{} holds {} {} more than the lots", wallet, wallet_left, name));
+        }
+    }
+
+    (allocations, warnings)
+}
+
+pub fn read_lot_allocation(file_path: &str) -> Result<Vec<LotAllocation>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut reader = csv::Reader::from_reader(file);
+    let mut allocations = vec![];
+    for allocation in reader.deserialize() {
+        allocations.push(allocation?);
+    }
+    Ok(allocations)
+}
+
+pub fn save_lot_allocation_to_file(allocations: &[LotAllocation], out_file: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(File::create(out_file)?);
+    for allocation in allocations {
+        writer.serialize(allocation)?;
+    }
+    writer.flush()?;
+    Ok(())
+}